@@ -0,0 +1,116 @@
+// explicit returns are the code style of this project
+#![allow(clippy::needless_return)]
+
+use std::hint::black_box;
+use std::path::PathBuf;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use minigrep::cli_input::parse_user_input_cli;
+use minigrep::fs_related::do_search;
+use minigrep::str_pattern_match::build_matcher;
+
+// streamed through a buffer
+const SMALL_CORPUS_SIZE: usize = 4 * 1024 * 1024;
+// above the size at which files are memory mapped
+const BIG_CORPUS_SIZE: usize = 16 * 1024 * 1024;
+
+const ASCII_WORDS: &[&str] = &[
+    "the", "of", "and", "search", "file", "directory", "pattern", "result", "value", "index",
+    "buffer", "String", "return", "match", "error", "config", "path", "walker", "token", "line",
+];
+const UTF8_WORDS: &[&str] = &[
+    "поиск", "файл", "строка", "значение", "ошибка", "каталог", "шаблон", "результат",
+    "検索", "ファイル", "文字列", "Straße", "naïve", "café", "smörgåsbord", "file",
+];
+// rare enough to make most lines miss, like in a typical search
+const NEEDLE: &str = "needle_42";
+const NEEDLE_EVERY_WORDS: u64 = 5000;
+
+// (name, arguments) of searches done over every corpus
+const SEARCHES: &[(&str, &[&str])] = &[
+    ("literal", &["-s", NEEDLE]),
+    ("literal_common", &["-s", "file"]),
+    ("ignore_case", &["-i", "-s", "NEEDLE_42"]),
+    ("whole_word", &["-w", "-s", NEEDLE]),
+    ("regex", &["-E", "-s", r"need\w+_\d+"]),
+    ("whole_line_regex", &["-x", "-E", "-s", r".*needle_\d+.*"]),
+    ("two_patterns", &["-s", NEEDLE, "-s", "walker"]),
+];
+
+// deterministic pseudo-random numbers, so corpora are the same between runs
+struct Lcg(u64);
+
+impl Lcg {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        return self.0 >> 33;
+    }
+}
+
+fn generate_corpus(words: &[&str], size: usize, seed: u64) -> String {
+    let mut rng = Lcg(seed);
+    let mut text = String::with_capacity(size + 128);
+    let mut words_in_line: u64 = 0;
+
+    while text.len() < size {
+        if rng.next().is_multiple_of(NEEDLE_EVERY_WORDS) {
+            text.push_str(NEEDLE);
+        } else {
+            text.push_str(words[(rng.next() % words.len() as u64) as usize]);
+        }
+        words_in_line += 1;
+
+        if words_in_line >= 6 + rng.next() % 10 {
+            text.push('\n');
+            words_in_line = 0;
+        } else {
+            text.push(' ');
+        }
+    }
+    text.push('\n');
+
+    return text;
+}
+
+fn write_corpus(name: &str, contents: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join("minigrep-bench");
+    std::fs::create_dir_all(&dir).expect("can't create directory for corpora");
+
+    let path = dir.join(name);
+    std::fs::write(&path, contents).expect("can't write corpus");
+    return path;
+}
+
+fn bench_throughput(c: &mut Criterion) {
+    let corpora = [
+        ("ascii_4m", generate_corpus(ASCII_WORDS, SMALL_CORPUS_SIZE, 1)),
+        ("ascii_16m", generate_corpus(ASCII_WORDS, BIG_CORPUS_SIZE, 2)),
+        ("utf8_4m", generate_corpus(UTF8_WORDS, SMALL_CORPUS_SIZE, 3)),
+        ("utf8_16m", generate_corpus(UTF8_WORDS, BIG_CORPUS_SIZE, 4)),
+    ];
+
+    for (corpus_name, contents) in corpora.iter() {
+        let corpus_path = write_corpus(corpus_name, contents);
+
+        let mut group = c.benchmark_group(*corpus_name);
+        group.throughput(Throughput::Bytes(contents.len() as u64));
+        group.sample_size(10);
+
+        for (search_name, search_args) in SEARCHES.iter() {
+            let mut args = vec!["minigrep".to_string(), "-q".to_string(), "-p".to_string(), corpus_path.to_string_lossy().into_owned()];
+            args.extend(search_args.iter().map(|arg| arg.to_string()));
+
+            let user_input = parse_user_input_cli(args);
+            let matcher = build_matcher(&user_input);
+
+            group.bench_function(BenchmarkId::from_parameter(search_name), |b| {
+                b.iter(|| black_box(do_search(&user_input, &matcher)));
+            });
+        }
+
+        group.finish();
+    }
+}
+
+criterion_group!(benches, bench_throughput);
+criterion_main!(benches);
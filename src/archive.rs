@@ -0,0 +1,98 @@
+use std::fs::File;
+use std::io::{BufReader, Read};
+
+use crate::decompress::decompressing_reader;
+
+// separates archive path from a member path in virtual file paths: bundle.zip!/src/main.rs
+pub const MEMBER_PATH_SEPARATOR: &str = "!/";
+
+const TAR_EXTENSIONS: &[&str] = &[".tar", ".tar.gz", ".tgz", ".tar.bz2", ".tbz2", ".tar.xz", ".txz", ".tar.zst", ".tzst"];
+const ZIP_EXTENSIONS: &[&str] = &[".zip", ".jar", ".war", ".ear"];
+
+#[derive(PartialEq, Clone, Copy)]
+pub enum ArchiveKind {
+    Tar,
+    Zip,
+}
+
+// archive format recognized by extension of `path`
+pub fn archive_kind(path: &str) -> Option<ArchiveKind> {
+    let path = path.to_ascii_lowercase();
+
+    if TAR_EXTENSIONS.iter().any(|ext| path.ends_with(ext)) {
+        return Some(ArchiveKind::Tar);
+    }
+    if ZIP_EXTENSIONS.iter().any(|ext| path.ends_with(ext)) {
+        return Some(ArchiveKind::Zip);
+    }
+
+    return None;
+}
+
+pub fn member_path(archive_path: &str, member_name: &str) -> String {
+    return format!("{}{}{}", archive_path, MEMBER_PATH_SEPARATOR, member_name);
+}
+
+// calls `on_member` with name and contents reader of every regular file in the archive, in archive order,
+// until it returns false; errors of a single zip member are passed to `on_error` and the rest is still read
+pub fn for_each_member<F, E>(archive_path: &str, kind: ArchiveKind, on_member: &mut F, on_error: &mut E) -> std::io::Result<()>
+where
+    F: FnMut(&str, &mut dyn Read) -> bool,
+    E: FnMut(&str, std::io::Error),
+{
+    match kind {
+        ArchiveKind::Tar => return for_each_tar_member(archive_path, on_member),
+        ArchiveKind::Zip => return for_each_zip_member(archive_path, on_member, on_error),
+    }
+}
+
+fn for_each_tar_member<F: FnMut(&str, &mut dyn Read) -> bool>(archive_path: &str, on_member: &mut F) -> std::io::Result<()> {
+    let file = File::open(archive_path)?;
+    // compressed tarballs are recognized by contents, the same way as with -z
+    let reader = decompressing_reader(BufReader::new(file))?;
+    let mut archive = tar::Archive::new(reader);
+
+    // tar is a stream, so an error in the middle of it leaves no way to find the next member
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+
+        let member_name = entry.path()?.to_string_lossy().into_owned();
+        if !on_member(&member_name, &mut entry) {
+            break;
+        }
+    }
+
+    return Ok(());
+}
+
+fn for_each_zip_member<F, E>(archive_path: &str, on_member: &mut F, on_error: &mut E) -> std::io::Result<()>
+where
+    F: FnMut(&str, &mut dyn Read) -> bool,
+    E: FnMut(&str, std::io::Error),
+{
+    let file = File::open(archive_path)?;
+    let mut archive = zip::ZipArchive::new(BufReader::new(file))?;
+
+    for index in 0..archive.len() {
+        let member_name = archive.name_for_index(index).unwrap_or("").to_string();
+        let mut member = match archive.by_index(index) {
+            Ok(member) => member,
+            Err(err) => {
+                on_error(&member_path(archive_path, &member_name), err.into());
+                continue;
+            }
+        };
+        if !member.is_file() {
+            continue;
+        }
+
+        if !on_member(&member_name, &mut member) {
+            break;
+        }
+    }
+
+    return Ok(());
+}
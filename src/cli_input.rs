@@ -1,5 +1,9 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use encoding_rs::Encoding;
+
 use crate::cli_output;
-use crate::fs_related::char_slice_to_str;
+use crate::fs_related::SortKey;
+use crate::query::QueryScope;
 
 // optional flags
 const OPT_FLAG_HELP: u8             = b'h';
@@ -8,8 +12,62 @@ const OPT_FLAG_RECURSIVE: u8        = b'r';
 const OPT_FLAG_LINE_NUMBERS: u8     = b'n';
 const OPT_FLAG_QUIET: u8            = b'q';
 const OPT_FLAG_IGNORE_CASE: u8      = b'i';
+const OPT_FLAG_REGEX: u8            = b'E';
+const OPT_FLAG_WHOLE_WORD: u8       = b'w';
+const OPT_FLAG_WHOLE_LINE: u8       = b'x';
+const OPT_FLAG_ONLY_MATCHING: u8    = b'o';
+const OPT_FLAG_FOLLOW_SYMLINKS: u8  = b'L';
+const OPT_FLAG_DECOMPRESS: u8       = b'z';
+const OPT_FLAG_MULTILINE: u8        = b'U';
+const OPT_FLAG_FILES_WITH_MATCHES: u8 = b'l';
+const OPT_FLAG_NULL: u8             = b'0';
+
+// long-only optional flags, values are outside of ascii so they never clash with short ones
+const OPT_FLAG_COLUMN: u8           = 128;
+const OPT_FLAG_IN_PLACE: u8         = 129;
+const OPT_FLAG_QUIT_ON_MATCH: u8    = 130;
+const OPT_FLAG_ONE_FILE_SYSTEM: u8  = 131;
+const OPT_FLAG_HIDDEN: u8           = 132;
+const OPT_FLAG_LOSSY: u8            = 133;
+const OPT_FLAG_ARCHIVES: u8         = 134;
+const OPT_FLAG_FUZZY_RANK: u8       = 135;
+const OPT_FLAG_BUILD_INDEX: u8      = 136;
+const OPT_FLAG_USE_INDEX: u8        = 137;
+const OPT_FLAG_WATCH: u8            = 138;
+const OPT_FLAG_FOLLOW: u8           = 139;
+const OPT_FLAG_INTERACTIVE: u8      = 140;
+const OPT_FLAG_VIMGREP: u8          = 141;
+const OPT_FLAG_HEADING: u8          = 142;
+const OPT_FLAG_NO_HEADING: u8       = 143;
+const OPT_FLAG_WITH_FILENAME: u8    = 144;
+const OPT_FLAG_NO_FILENAME: u8      = 145;
+const OPT_FLAG_STATS: u8            = 146;
+const OPT_FLAG_STATS_JSON: u8       = 147;
+const OPT_FLAG_EXECUTABLE: u8       = 148;
+
+const LONG_OPT_FLAG_COLUMN: &str        = "column";
+const LONG_OPT_FLAG_IN_PLACE: &str      = "in-place";
+const LONG_OPT_FLAG_QUIT_ON_MATCH: &str = "quit-on-match";
+const LONG_OPT_FLAG_ONE_FILE_SYSTEM: &str = "one-file-system";
+const LONG_OPT_FLAG_HIDDEN: &str = "hidden";
+const LONG_OPT_FLAG_LOSSY: &str = "lossy";
+const LONG_OPT_FLAG_ARCHIVES: &str = "archives";
+const LONG_OPT_FLAG_FUZZY_RANK: &str = "fuzzy-rank";
+const LONG_OPT_FLAG_BUILD_INDEX: &str = "build-index";
+const LONG_OPT_FLAG_USE_INDEX: &str = "use-index";
+const LONG_OPT_FLAG_WATCH: &str = "watch";
+const LONG_OPT_FLAG_FOLLOW: &str = "follow";
+const LONG_OPT_FLAG_INTERACTIVE: &str = "interactive";
+const LONG_OPT_FLAG_VIMGREP: &str = "vimgrep";
+const LONG_OPT_FLAG_HEADING: &str = "heading";
+const LONG_OPT_FLAG_NO_HEADING: &str = "no-heading";
+const LONG_OPT_FLAG_WITH_FILENAME: &str = "with-filename";
+const LONG_OPT_FLAG_NO_FILENAME: &str = "no-filename";
+const LONG_OPT_FLAG_STATS: &str = "stats";
+const LONG_OPT_FLAG_STATS_JSON: &str = "stats-json";
+const LONG_OPT_FLAG_EXECUTABLE: &str = "executable";
 
-#[derive(PartialEq)]
+#[derive(Clone, PartialEq)]
 #[repr(u8)]
 pub enum OptFlag {
     Help = OPT_FLAG_HELP,
@@ -18,6 +76,36 @@ pub enum OptFlag {
     LineNumbers = OPT_FLAG_LINE_NUMBERS,
     Quiet = OPT_FLAG_QUIET,
     IgnoreCase = OPT_FLAG_IGNORE_CASE,
+    Regex = OPT_FLAG_REGEX,
+    WholeWord = OPT_FLAG_WHOLE_WORD,
+    WholeLine = OPT_FLAG_WHOLE_LINE,
+    OnlyMatching = OPT_FLAG_ONLY_MATCHING,
+    FollowSymlinks = OPT_FLAG_FOLLOW_SYMLINKS,
+    Decompress = OPT_FLAG_DECOMPRESS,
+    Multiline = OPT_FLAG_MULTILINE,
+    FilesWithMatches = OPT_FLAG_FILES_WITH_MATCHES,
+    Null = OPT_FLAG_NULL,
+    Column = OPT_FLAG_COLUMN,
+    InPlace = OPT_FLAG_IN_PLACE,
+    QuitOnMatch = OPT_FLAG_QUIT_ON_MATCH,
+    OneFileSystem = OPT_FLAG_ONE_FILE_SYSTEM,
+    Hidden = OPT_FLAG_HIDDEN,
+    Lossy = OPT_FLAG_LOSSY,
+    Archives = OPT_FLAG_ARCHIVES,
+    FuzzyRank = OPT_FLAG_FUZZY_RANK,
+    BuildIndex = OPT_FLAG_BUILD_INDEX,
+    UseIndex = OPT_FLAG_USE_INDEX,
+    Watch = OPT_FLAG_WATCH,
+    Follow = OPT_FLAG_FOLLOW,
+    Interactive = OPT_FLAG_INTERACTIVE,
+    Vimgrep = OPT_FLAG_VIMGREP,
+    Heading = OPT_FLAG_HEADING,
+    NoHeading = OPT_FLAG_NO_HEADING,
+    WithFilename = OPT_FLAG_WITH_FILENAME,
+    NoFilename = OPT_FLAG_NO_FILENAME,
+    Stats = OPT_FLAG_STATS,
+    StatsJson = OPT_FLAG_STATS_JSON,
+    Executable = OPT_FLAG_EXECUTABLE,
 }
 
 // non-optional flags (argument expected)
@@ -25,22 +113,95 @@ const FLAG_SEARCH: u8 = b's';
 const FLAG_OUTPUT_TO_FILE: u8 = b'f';
 const FLAG_PATH: u8 = b'p';
 const FLAG_EXCLUDE_PATHS: u8 = b'e';
+const FLAG_MAX_COUNT: u8 = b'm';
 
+// long non-optional flags
+const LONG_FLAG_REPLACE: &str = "replace";
+const LONG_FLAG_MAX_TOTAL: &str = "max-total";
+const LONG_FLAG_MAX_DEPTH: &str = "max-depth";
+const LONG_FLAG_MIN_DEPTH: &str = "min-depth";
+const LONG_FLAG_ENCODING: &str = "encoding";
+const LONG_FLAG_PRE: &str = "pre";
+const LONG_FLAG_PRE_GLOB: &str = "pre-glob";
+const LONG_FLAG_FUZZY: &str = "fuzzy";
+const LONG_FLAG_TAIL: &str = "tail";
+const LONG_FLAG_QUERY: &str = "query";
+const LONG_FLAG_QUERY_SCOPE: &str = "query-scope";
+const LONG_FLAG_FIELD_SEP: &str = "field-sep";
+const LONG_FLAG_CONTEXT_SEP: &str = "context-sep";
+const LONG_FLAG_SORT: &str = "sort";
+const LONG_FLAG_SORT_REVERSED: &str = "sortr";
+const LONG_FLAG_MAX_FILESIZE: &str = "max-filesize";
+const LONG_FLAG_NEWER_THAN: &str = "newer-than";
+const LONG_FLAG_OLDER_THAN: &str = "older-than";
+const LONG_FLAG_OWNER: &str = "owner";
+
+#[derive(Clone)]
 pub struct UserInput {
-    pub search_pattern:     String,
+    pub search_patterns:    Vec<String>,
     pub search_path:        String,
     pub output_file_path:   Option<String>,
     pub exclude_paths:      Option<Vec<String>>,
+    pub replacement:        Option<String>,
+    // limit of matches per file
+    pub max_count:          Option<usize>,
+    // limit of matches for the whole search
+    pub max_total:          Option<usize>,
+    // depth limits of a directory walk, entries of the search path have depth 1
+    pub max_depth:          Option<usize>,
+    pub min_depth:          Option<usize>,
+    pub encoding:           Option<&'static Encoding>,
+    // command which output is searched instead of a file, for files matching any of the globs
+    pub preprocessor:       Option<String>,
+    pub preprocessor_globs: Option<Vec<String>>,
+    // max edit distance of approximate matches
+    pub fuzzy:              Option<usize>,
+    // only this many last lines of a file are searched (with --follow)
+    pub tail_lines:         Option<usize>,
+    // boolean expression over patterns, used instead of -s patterns
+    pub query:              Option<String>,
+    pub query_scope:        QueryScope,
+    // separators of fields in the flat layout, after matched lines and after context lines
+    pub field_separator:    Option<String>,
+    pub context_separator:  Option<String>,
+    // order of results, by default files are searched in the order directories list them
+    pub sort_by:            Option<SortKey>,
+    pub sort_descending:    bool,
+    // filters of files in directory search by their metadata, times are of the last modification
+    pub max_filesize:       Option<u64>,
+    pub newer_than:         Option<SystemTime>,
+    pub older_than:         Option<SystemTime>,
+    pub owner_uid:          Option<u32>,
     pub opt_flags:          Vec<OptFlag>
 }
 
 impl UserInput {
     pub fn new_empty() -> Self {
         Self {
-            search_pattern:     String::new(),
+            search_patterns:    Vec::<String>::new(),
             search_path:        String::new(),
             output_file_path:   None,
             exclude_paths:      None,
+            replacement:        None,
+            max_count:          None,
+            max_total:          None,
+            max_depth:          None,
+            min_depth:          None,
+            encoding:           None,
+            preprocessor:       None,
+            preprocessor_globs: None,
+            fuzzy:              None,
+            tail_lines:         None,
+            query:              None,
+            query_scope:        QueryScope::Line,
+            field_separator:    None,
+            context_separator:  None,
+            sort_by:            None,
+            sort_descending:    false,
+            max_filesize:       None,
+            newer_than:         None,
+            older_than:         None,
+            owner_uid:          None,
             opt_flags:          Vec::<OptFlag>::new()
         }
     }
@@ -62,11 +223,35 @@ pub fn parse_user_input_cli(input: Vec<String>) -> UserInput {
     'outer: while let Some(curr_cli_arg) = it.next() {
         is_on_flag = false;
 
+        if let Some(long_flag) = curr_cli_arg.strip_prefix("--") {
+            if let Some(opt_flag) = match_long_opt_flag(long_flag) {
+                user_input_parsed.opt_flags.push(opt_flag);
+            }
+            else if is_long_non_opt_flag(long_flag) {
+                match it.next() {
+                    Some(next_cli_arg) => {
+                        match_long_non_opt_flag(long_flag, next_cli_arg, &mut user_input_parsed);
+                    },
+                    None => {
+                        eprintln!("Argument for non-optional flag --{} is missing", long_flag);
+                        cli_output::print_help_info();
+                        std::process::exit(1);
+                    }
+                }
+            }
+            else {
+                eprintln!("Unknown flag provided: --{}", long_flag);
+                cli_output::print_opt_flags();
+                std::process::exit(1);
+            }
+            continue 'outer;
+        }
+
         'inner: for c in curr_cli_arg.bytes() {
             if c == b'-' {
                 // multiple flags (only optional flags that do not require arguments)
                 if curr_cli_arg.len() > 2 {
-                    user_input_parsed.opt_flags = parse_mult_opt_flags(&curr_cli_arg[1..]);
+                    user_input_parsed.opt_flags.extend(parse_mult_opt_flags(&curr_cli_arg[1..]));
                     continue 'outer;
                 }
                 else {
@@ -127,10 +312,11 @@ fn parse_mult_opt_flags(flags: &str) -> Vec<OptFlag> {
 
 fn match_non_opt_flag(flag: u8, argument: String, user_input: &mut UserInput) {
     match flag {
-        FLAG_SEARCH => user_input.search_pattern = argument,
+        FLAG_SEARCH => user_input.search_patterns.push(argument),
         FLAG_PATH => user_input.search_path = argument,
         FLAG_OUTPUT_TO_FILE => user_input.output_file_path = Some(argument),
-        FLAG_EXCLUDE_PATHS => user_input.exclude_paths = parse_exclude_paths(&argument, ','),
+        FLAG_EXCLUDE_PATHS => user_input.exclude_paths = parse_list_arg(&argument, ','),
+        FLAG_MAX_COUNT => user_input.max_count = Some(parse_number_arg("-m", &argument)),
         _ => unreachable!(),
     }
 }
@@ -143,58 +329,375 @@ fn match_opt_flag(opt_flag: u8) -> OptFlag {
         OPT_FLAG_LINE_NUMBERS => OptFlag::LineNumbers,
         OPT_FLAG_QUIET => OptFlag::Quiet,
         OPT_FLAG_IGNORE_CASE => OptFlag::IgnoreCase,
+        OPT_FLAG_REGEX => OptFlag::Regex,
+        OPT_FLAG_WHOLE_WORD => OptFlag::WholeWord,
+        OPT_FLAG_WHOLE_LINE => OptFlag::WholeLine,
+        OPT_FLAG_ONLY_MATCHING => OptFlag::OnlyMatching,
+        OPT_FLAG_FOLLOW_SYMLINKS => OptFlag::FollowSymlinks,
+        OPT_FLAG_DECOMPRESS => OptFlag::Decompress,
+        OPT_FLAG_MULTILINE => OptFlag::Multiline,
+        OPT_FLAG_FILES_WITH_MATCHES => OptFlag::FilesWithMatches,
+        OPT_FLAG_NULL => OptFlag::Null,
         _ => unreachable!(),
     }
 }
 
-fn is_non_opt_flag(flag: u8) -> bool {
-    match flag {
-        FLAG_SEARCH => true,
-        FLAG_PATH => true,
-        FLAG_OUTPUT_TO_FILE => true,
-        FLAG_EXCLUDE_PATHS => true,
-        _ => false,
+fn match_long_opt_flag(long_flag: &str) -> Option<OptFlag> {
+    return match long_flag {
+        LONG_OPT_FLAG_COLUMN => Some(OptFlag::Column),
+        LONG_OPT_FLAG_IN_PLACE => Some(OptFlag::InPlace),
+        LONG_OPT_FLAG_QUIT_ON_MATCH => Some(OptFlag::QuitOnMatch),
+        LONG_OPT_FLAG_ONE_FILE_SYSTEM => Some(OptFlag::OneFileSystem),
+        LONG_OPT_FLAG_HIDDEN => Some(OptFlag::Hidden),
+        LONG_OPT_FLAG_LOSSY => Some(OptFlag::Lossy),
+        LONG_OPT_FLAG_ARCHIVES => Some(OptFlag::Archives),
+        LONG_OPT_FLAG_FUZZY_RANK => Some(OptFlag::FuzzyRank),
+        LONG_OPT_FLAG_BUILD_INDEX => Some(OptFlag::BuildIndex),
+        LONG_OPT_FLAG_USE_INDEX => Some(OptFlag::UseIndex),
+        LONG_OPT_FLAG_WATCH => Some(OptFlag::Watch),
+        LONG_OPT_FLAG_FOLLOW => Some(OptFlag::Follow),
+        LONG_OPT_FLAG_INTERACTIVE => Some(OptFlag::Interactive),
+        LONG_OPT_FLAG_VIMGREP => Some(OptFlag::Vimgrep),
+        LONG_OPT_FLAG_HEADING => Some(OptFlag::Heading),
+        LONG_OPT_FLAG_NO_HEADING => Some(OptFlag::NoHeading),
+        LONG_OPT_FLAG_WITH_FILENAME => Some(OptFlag::WithFilename),
+        LONG_OPT_FLAG_NO_FILENAME => Some(OptFlag::NoFilename),
+        LONG_OPT_FLAG_STATS => Some(OptFlag::Stats),
+        LONG_OPT_FLAG_STATS_JSON => Some(OptFlag::StatsJson),
+        LONG_OPT_FLAG_EXECUTABLE => Some(OptFlag::Executable),
+        _ => None,
+    };
+}
+
+fn match_long_non_opt_flag(long_flag: &str, argument: String, user_input: &mut UserInput) {
+    match long_flag {
+        LONG_FLAG_REPLACE => user_input.replacement = Some(argument),
+        LONG_FLAG_MAX_TOTAL => user_input.max_total = Some(parse_number_arg("--max-total", &argument)),
+        LONG_FLAG_MAX_DEPTH => user_input.max_depth = Some(parse_number_arg("--max-depth", &argument)),
+        LONG_FLAG_MIN_DEPTH => user_input.min_depth = Some(parse_number_arg("--min-depth", &argument)),
+        LONG_FLAG_ENCODING => user_input.encoding = Some(parse_encoding_arg(&argument)),
+        LONG_FLAG_PRE => user_input.preprocessor = Some(argument),
+        LONG_FLAG_PRE_GLOB => user_input.preprocessor_globs = parse_list_arg(&argument, ','),
+        LONG_FLAG_FUZZY => user_input.fuzzy = Some(parse_number_arg("--fuzzy", &argument)),
+        LONG_FLAG_TAIL => user_input.tail_lines = Some(parse_number_arg("--tail", &argument)),
+        LONG_FLAG_QUERY => user_input.query = Some(argument),
+        LONG_FLAG_QUERY_SCOPE => user_input.query_scope = parse_query_scope_arg(&argument),
+        LONG_FLAG_FIELD_SEP => user_input.field_separator = Some(argument),
+        LONG_FLAG_CONTEXT_SEP => user_input.context_separator = Some(argument),
+        LONG_FLAG_SORT => {
+            user_input.sort_by = Some(parse_sort_arg(&argument));
+            user_input.sort_descending = false;
+        },
+        LONG_FLAG_SORT_REVERSED => {
+            user_input.sort_by = Some(parse_sort_arg(&argument));
+            user_input.sort_descending = true;
+        },
+        LONG_FLAG_MAX_FILESIZE => user_input.max_filesize = Some(parse_size_arg("--max-filesize", &argument)),
+        LONG_FLAG_NEWER_THAN => user_input.newer_than = Some(parse_time_arg("--newer-than", &argument)),
+        LONG_FLAG_OLDER_THAN => user_input.older_than = Some(parse_time_arg("--older-than", &argument)),
+        LONG_FLAG_OWNER => user_input.owner_uid = Some(parse_owner_arg(&argument)),
+        _ => unreachable!(),
     }
 }
 
+fn is_long_non_opt_flag(long_flag: &str) -> bool {
+    return matches!(long_flag,
+        LONG_FLAG_REPLACE |
+        LONG_FLAG_MAX_TOTAL |
+        LONG_FLAG_MAX_DEPTH |
+        LONG_FLAG_MIN_DEPTH |
+        LONG_FLAG_ENCODING |
+        LONG_FLAG_PRE |
+        LONG_FLAG_PRE_GLOB |
+        LONG_FLAG_FUZZY |
+        LONG_FLAG_TAIL |
+        LONG_FLAG_QUERY |
+        LONG_FLAG_QUERY_SCOPE |
+        LONG_FLAG_FIELD_SEP |
+        LONG_FLAG_CONTEXT_SEP |
+        LONG_FLAG_SORT |
+        LONG_FLAG_SORT_REVERSED |
+        LONG_FLAG_MAX_FILESIZE |
+        LONG_FLAG_NEWER_THAN |
+        LONG_FLAG_OLDER_THAN |
+        LONG_FLAG_OWNER
+    );
+}
+
+fn is_non_opt_flag(flag: u8) -> bool {
+    return matches!(flag,
+        FLAG_SEARCH |
+        FLAG_PATH |
+        FLAG_OUTPUT_TO_FILE |
+        FLAG_EXCLUDE_PATHS |
+        FLAG_MAX_COUNT
+    );
+}
+
 fn is_opt_flag(opt_flag: u8) -> bool {
-    match opt_flag {
-        OPT_FLAG_HELP => true,
-        OPT_FLAG_DIR => true,
-        OPT_FLAG_RECURSIVE => true,
-        OPT_FLAG_LINE_NUMBERS => true,
-        OPT_FLAG_QUIET => true,
-        OPT_FLAG_IGNORE_CASE => true,
-        _ => false,
+    return matches!(opt_flag,
+        OPT_FLAG_HELP |
+        OPT_FLAG_DIR |
+        OPT_FLAG_RECURSIVE |
+        OPT_FLAG_LINE_NUMBERS |
+        OPT_FLAG_QUIET |
+        OPT_FLAG_IGNORE_CASE |
+        OPT_FLAG_REGEX |
+        OPT_FLAG_WHOLE_WORD |
+        OPT_FLAG_WHOLE_LINE |
+        OPT_FLAG_ONLY_MATCHING |
+        OPT_FLAG_FOLLOW_SYMLINKS |
+        OPT_FLAG_DECOMPRESS |
+        OPT_FLAG_MULTILINE |
+        OPT_FLAG_FILES_WITH_MATCHES |
+        OPT_FLAG_NULL
+    );
+}
+
+fn parse_number_arg(flag_name: &str, argument: &str) -> usize {
+    match argument.parse::<usize>() {
+        Ok(num) => return num,
+        Err(_) => {
+            eprintln!("Argument for flag {} should be a non-negative number, got: {}", flag_name, argument);
+            cli_output::print_arg_flags();
+            std::process::exit(1);
+        }
     }
 }
 
-fn parse_exclude_paths(exclude_paths: &String, splitter: char) -> Option<Vec<String>> {
-    let exclude_paths = split_str_into_vec(&exclude_paths, splitter);
-    if exclude_paths.len() == 0 {
-        return None;
-    } else {
-        return Some(exclude_paths);
+fn parse_encoding_arg(argument: &str) -> &'static Encoding {
+    match Encoding::for_label(argument.as_bytes()) {
+        Some(encoding) => return encoding,
+        None => {
+            eprintln!("Unknown encoding provided: {}", argument);
+            eprintln!("Examples of supported encodings: utf-8, utf-16le, utf-16be, latin1, windows-1251, koi8-r");
+            std::process::exit(1);
+        }
+    }
+}
+
+fn parse_query_scope_arg(argument: &str) -> QueryScope {
+    match argument {
+        "line" => return QueryScope::Line,
+        "file" => return QueryScope::File,
+        _ => {
+            eprintln!("Unknown query scope provided: {}", argument);
+            eprintln!("Query scope can be: line, file");
+            std::process::exit(1);
+        }
+    }
+}
+
+fn parse_sort_arg(argument: &str) -> SortKey {
+    match argument {
+        "path" => return SortKey::Path,
+        "modified" => return SortKey::Modified,
+        "created" => return SortKey::Created,
+        "size" => return SortKey::Size,
+        "matches" => return SortKey::Matches,
+        _ => {
+            eprintln!("Unknown sort key provided: {}", argument);
+            eprintln!("Results can be sorted by: path, modified, created, size, matches");
+            std::process::exit(1);
+        }
+    }
+}
+
+// bytes, or with a K, M or G suffix (powers of 1024)
+fn parse_size_arg(flag_name: &str, argument: &str) -> u64 {
+    let (digits, multiplier) = match argument.char_indices().last() {
+        Some((ind, 'K' | 'k')) => (&argument[..ind], 1 << 10),
+        Some((ind, 'M' | 'm')) => (&argument[..ind], 1 << 20),
+        Some((ind, 'G' | 'g')) => (&argument[..ind], 1 << 30),
+        _ => (argument, 1),
+    };
+
+    match digits.parse::<u64>().ok().and_then(|num| num.checked_mul(multiplier)) {
+        Some(size) => return size,
+        None => {
+            eprintln!("Argument for flag {} should be a size in bytes, optionally with K, M or G suffix, got: {}", flag_name, argument);
+            std::process::exit(1);
+        }
     }
 }
 
-fn split_str_into_vec(s: &String, splitter: char) -> Vec<String> {
-    let mut result = Vec::<String>::with_capacity(s.len() / 5);
-    let char_vec: Vec<char> = s.chars().collect();
-    let char_vec_last_ind = char_vec.len() - 1;
-    let mut curr_slice_start: usize = 0;
+// an age relative to now (30m, 2h, 7d) or a date in UTC (2026-01-01, 2026-01-01T12:00:00)
+fn parse_time_arg(flag_name: &str, argument: &str) -> SystemTime {
+    let time = parse_age(argument)
+        .and_then(|age| SystemTime::now().checked_sub(age))
+        .or_else(|| parse_date(argument));
 
-    for (ind, ch) in char_vec.iter().enumerate() {
-        if *ch == splitter && ((ind - curr_slice_start) > 0) {
-            let splitted_part = char_slice_to_str(&char_vec[curr_slice_start..ind]);
-            result.push(splitted_part);
-            curr_slice_start = ind + 1;
+    match time {
+        Some(time) => return time,
+        None => {
+            eprintln!("Argument for flag {} should be an age like 30m, 2h, 7d or a date like 2026-01-01, got: {}", flag_name, argument);
+            std::process::exit(1);
         }
-        else if ind == char_vec_last_ind && (ind - curr_slice_start > 0) {
-            let splitted_part = char_slice_to_str(&char_vec[curr_slice_start..ind]);
-            result.push(splitted_part);
+    }
+}
+
+fn parse_age(argument: &str) -> Option<Duration> {
+    let (ind, unit) = argument.char_indices().last()?;
+    let unit_secs = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        'w' => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+    let num = argument[..ind].parse::<u64>().ok()?;
+    return Some(Duration::from_secs(num.checked_mul(unit_secs)?));
+}
+
+fn parse_date(argument: &str) -> Option<SystemTime> {
+    let (date, time) = match argument.split_once(['T', ' ']) {
+        Some((date, time)) => (date, Some(time)),
+        None => (argument, None),
+    };
+
+    let date_parts: Vec<&str> = date.split('-').collect();
+    if date_parts.len() != 3 {
+        return None;
+    }
+    let year = date_parts[0].parse::<i64>().ok()?;
+    let month = date_parts[1].parse::<u32>().ok().filter(|month| (1..=12).contains(month))?;
+    let day = date_parts[2].parse::<u32>().ok().filter(|day| (1..=days_in_month(year, month)).contains(day))?;
+
+    let mut day_secs: u64 = 0;
+    if let Some(time) = time {
+        let time_parts: Vec<&str> = time.split(':').collect();
+        if time_parts.len() < 2 || time_parts.len() > 3 {
+            return None;
         }
+        let hours = time_parts[0].parse::<u64>().ok().filter(|hours| *hours < 24)?;
+        let minutes = time_parts[1].parse::<u64>().ok().filter(|minutes| *minutes < 60)?;
+        let seconds = match time_parts.get(2) {
+            Some(seconds) => seconds.parse::<u64>().ok().filter(|seconds| *seconds < 60)?,
+            None => 0,
+        };
+        day_secs = hours * 3600 + minutes * 60 + seconds;
     }
 
-    return result;
+    let days = days_from_civil(year, month, day);
+    if days < 0 {
+        return None;
+    }
+    return UNIX_EPOCH.checked_add(Duration::from_secs(days as u64 * 86400 + day_secs));
+}
+
+fn days_in_month(year: i64, month: u32) -> u32 {
+    return match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    };
+}
+
+// days since 1970-01-01 of a date in the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    // years start in March, so the leap day is the last day of a year
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let month_from_march = (month as i64 + 9) % 12;
+    let day_of_year = (153 * month_from_march + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    return era * 146097 + day_of_era - 719468;
+}
+
+// a uid or a user name from /etc/passwd
+fn parse_owner_arg(argument: &str) -> u32 {
+    if let Ok(uid) = argument.parse::<u32>() {
+        return uid;
+    }
+
+    let passwd = std::fs::read_to_string("/etc/passwd").unwrap_or_default();
+    for line in passwd.lines() {
+        let fields: Vec<&str> = line.split(':').collect();
+        if fields.len() > 2 && fields[0] == argument {
+            if let Ok(uid) = fields[2].parse::<u32>() {
+                return uid;
+            }
+        }
+    }
+
+    eprintln!("Unknown owner provided: {}", argument);
+    eprintln!("Owner can be a user name or a numeric uid");
+    std::process::exit(1);
+}
+
+fn parse_list_arg(list: &str, splitter: char) -> Option<Vec<String>> {
+    let items = split_str_into_vec(list, splitter);
+    if items.is_empty() {
+        return None;
+    } else {
+        return Some(items);
+    }
+}
+
+fn split_str_into_vec(s: &str, splitter: char) -> Vec<String> {
+    return s.split(splitter)
+        .filter(|part| !part.is_empty())
+        .map(|part| part.to_string())
+        .collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date_secs(argument: &str) -> Option<u64> {
+        return parse_date(argument).map(|time| time.duration_since(UNIX_EPOCH).unwrap().as_secs());
+    }
+
+    #[test]
+    fn days_from_civil_counts_from_epoch() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(1970, 3, 1), 59);
+        assert_eq!(days_from_civil(2000, 3, 1), 11017);
+        assert_eq!(days_from_civil(2026, 1, 1), 20454);
+        assert_eq!(days_from_civil(1969, 12, 31), -1);
+    }
+
+    #[test]
+    fn leap_days() {
+        assert_eq!(days_from_civil(2024, 3, 1) - days_from_civil(2024, 2, 28), 2);
+        assert_eq!(days_from_civil(2100, 3, 1) - days_from_civil(2100, 2, 28), 1);
+        assert_eq!(days_from_civil(2000, 3, 1) - days_from_civil(2000, 2, 28), 2);
+    }
+
+    #[test]
+    fn parses_dates_and_times() {
+        assert_eq!(date_secs("1970-01-01"), Some(0));
+        assert_eq!(date_secs("2026-01-01"), Some(20454 * 86400));
+        assert_eq!(date_secs("2026-01-01T12:30"), Some(20454 * 86400 + 12 * 3600 + 30 * 60));
+        assert_eq!(date_secs("2026-01-01 00:00:59"), Some(20454 * 86400 + 59));
+        assert_eq!(date_secs("2024-02-29"), Some(19782 * 86400));
+    }
+
+    #[test]
+    fn rejects_invalid_dates() {
+        assert_eq!(date_secs("2026-02-31"), None);
+        assert_eq!(date_secs("2026-02-29"), None);
+        assert_eq!(date_secs("2026-04-31"), None);
+        assert_eq!(date_secs("2026-13-01"), None);
+        assert_eq!(date_secs("2026-00-10"), None);
+        assert_eq!(date_secs("2026-01-00"), None);
+        assert_eq!(date_secs("2026-01"), None);
+        assert_eq!(date_secs("2026-01-01T24:00"), None);
+        assert_eq!(date_secs("2026-01-01T12"), None);
+        assert_eq!(date_secs("1960-01-01"), None);
+    }
+
+    #[test]
+    fn parses_ages() {
+        assert_eq!(parse_age("30s"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_age("10m"), Some(Duration::from_secs(600)));
+        assert_eq!(parse_age("2h"), Some(Duration::from_secs(7200)));
+        assert_eq!(parse_age("1w"), Some(Duration::from_secs(7 * 86400)));
+        assert_eq!(parse_age("2026-01-01"), None);
+        assert_eq!(parse_age("h"), None);
+    }
 }
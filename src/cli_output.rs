@@ -1,9 +1,13 @@
 use std::fs::File;
-use std::io::{ErrorKind, Write};
+use std::io::{ErrorKind, IsTerminal, Write};
+use std::time::Duration;
 
 use crate::cli_input::{OptFlag, UserInput};
-use crate::fs_related::{FileData, Token};
-use crate::str_pattern_match;
+use crate::fs_related::{FileData, FileReplaceData, SearchStats, Token};
+use crate::str_pattern_match::Occurence;
+
+// unchanged lines shown around every change in a diff
+const DIFF_CONTEXT_LINES: usize = 3;
 
 pub fn print_help_info() {
     println!("General Usage:");
@@ -20,82 +24,466 @@ pub fn print_opt_flags() {
     println!("\t-r -- do recursive search starting from $filepath");
     println!("\t-i -- ignore case in $pattern and occurences");
     println!("\t-n -- output line numbers");
+    println!("\t-E -- treat $pattern as a regular expression");
+    println!("\t-w -- match $pattern only as a whole word");
+    println!("\t-x -- match $pattern only as a whole line, output lines instead of words");
+    println!("\t-o -- output only matched parts of lines, each match on a separate line");
+    println!("\t-L -- follow symbolic links to files and directories, loops are detected and skipped");
+    println!("\t-U -- multiline search, $pattern is matched against whole files and may span a few lines (\\n with -E)");
+    println!("\t-z -- search inside of compressed files (gzip, bzip2, xz, zstd), detected by their contents");
+    println!("\t-l -- output only paths of files with matches, one per line");
+    println!("\t-0 -- end every path with a NUL byte instead of a newline (with -l), for xargs -0");
+    println!("\t--column -- output column of each match (with -o)");
+    println!("\t--in-place -- write replacements into files, instead of printing a diff (with --replace)");
+    println!("\t--quit-on-match -- stop the whole search after the first match");
+    println!("\t--one-file-system -- don't descend into directories on other filesystems (mounts)");
+    println!("\t--hidden -- search hidden files and directories (names starting with '.'), skipped by default");
+    println!("\t--lossy -- replace invalid bytes in files instead of skipping such files");
+    println!("\t--fuzzy-rank -- output closest approximate matches first (with --fuzzy)");
+    println!("\t--archives -- search members of .tar (also compressed), .zip and .jar archives as files named archive!/member");
+    println!("\t--build-index -- build or update the index of files right in $filepath directory (of the whole tree with -r), only changed files are read again");
+    println!("\t--use-index -- skip files which can't contain $pattern according to the index of $filepath, changed files are still searched");
+    println!("\t--watch -- keep running after the search, files created or changed under $filepath are searched again and new (+) or removed (-) matches are output");
+    println!("\t--follow -- keep reading $filepath as it grows, like tail -f, new matching lines are output; rotated or truncated files are reopened");
+    println!("\t--interactive -- full screen interface: type the pattern (or --query) and see matches as they are found,\nUp/Down select a match with a preview around it, Enter opens it in $EDITOR at its line, Esc quits");
+    println!("\t--vimgrep -- output every match as path:line:column:text, for editors (Vim grepprg, Emacs compilation-mode, VS Code problem matchers)");
+    println!("\t--heading -- group matches under the file path, default when output goes to a terminal");
+    println!("\t--no-heading -- output grep's flat path:line:text lines, default when output goes to a pipe or a file (-f)");
+    println!("\t--with-filename -- always output the file path (by default flat output of a single file omits it)");
+    println!("\t--no-filename -- never output the file path");
+    println!("\t--stats -- after the search output counts of searched, matched and skipped files, matches, bytes read and elapsed time");
+    println!("\t--stats-json -- same as --stats, as a single line JSON object for scripts");
+    println!("\t--executable -- only search files with an execute permission bit set (in directory search)");
 }
 
 pub fn print_arg_flags() {
     println!("You can also include options that accept an argument:");
+    println!("\t-s -- pattern to search, can be repeated to search for any of a few patterns:\n-s foo -s bar");
     println!("\t-f -- write all program output to the file, instead of stdin:\n-f $output_to_file_path");
     println!("\t-e -- exclude searching from paths which contain patterns:\n-e .git,.png,.exe");
+    println!("\t--replace -- replace matches, with -E $1 or ${{name}} refer to capture groups,\nprints a diff unless --in-place is provided: --replace $replacement");
+    println!("\t-m -- stop reading a file after N matches in it: -m $max_count");
+    println!("\t--max-total -- stop the whole search after N matches: --max-total $max_total");
+    println!("\t--max-depth -- don't search deeper than N levels below $filepath: --max-depth $depth");
+    println!("\t--min-depth -- only search files at least N levels below $filepath: --min-depth $depth");
+    println!("\t--encoding -- encoding of searched files, by default utf-8 or detected by byte order mark:\n--encoding windows-1251");
+    println!("\t--pre -- command which gets a file path and which output is searched instead of the file,\nfor formats like pdf or docx: --pre pdftotext-stdout");
+    println!("\t--pre-glob -- only files matching any of the globs go through --pre (all files by default):\n--pre-glob '*.pdf,docs/**/*.docx'");
+    println!("\t--fuzzy -- match $pattern approximately, with up to N typos (inserted, deleted or changed chars),\ndistance of every match is shown: --fuzzy $max_distance");
+    println!("\t--tail -- only search the last N lines of $filepath before following it (with --follow): --tail $lines");
+    println!("\t--query -- boolean query instead of -s patterns, with AND, OR, NOT and parentheses: --query 'timeout AND NOT retry'");
+    println!("\t--query-scope -- evaluate --query per line (default) or over whole files, matches of not negated patterns are output: --query-scope file");
+    println!("\t--field-sep -- separator of path, line number, column and text in flat output (':' by default): --field-sep $separator");
+    println!("\t--context-sep -- separator used instead of --field-sep for context lines, the lines of a multiline match after the first one ('-' by default): --context-sep $separator");
+    println!("\t--sort -- sort results in ascending order by path, modified, created, size or matches (count of matches in a file),\nties keep the path order: --sort modified");
+    println!("\t--sortr -- same as --sort, in descending order: --sortr size");
+    println!("\t--max-filesize -- skip files bigger than the size in bytes, K, M and G suffixes are accepted (in directory search): --max-filesize 10M");
+    println!("\t--newer-than -- only search files modified after the time, an age (s, m, h, d, w) or a date in UTC (in directory search):\n--newer-than 2h");
+    println!("\t--older-than -- only search files modified before the time, same format as --newer-than (in directory search):\n--older-than 2026-01-01");
+    println!("\t--owner -- only search files owned by the user, a name or a uid (in directory search): --owner $user");
 }
 
 
-pub fn print_occurences_in_file(pattern: &String, file_data: FileData, user_input: &UserInput) {
-    let ignore_case = user_input.has_opt_flag(OptFlag::IgnoreCase);
+pub fn print_occurences_in_file(file_data: &FileData, user_input: &UserInput) {
+    let stdout = std::io::stdout();
+    let mut stdout_lock = stdout.lock();
+    // stdout may be closed by a pipe reader, nothing to report then
+    let _ = write_occurences(&mut stdout_lock, file_data, user_input, stdout.is_terminal());
+}
 
-    let occurences = str_pattern_match::find_occurences(file_data.file_tokens, &pattern, ignore_case);
-    if occurences.len() == 0 {
-        return;
+pub fn write_occurences_to_output_file(file_data: &FileData, output_file_path: &String, user_input: &UserInput) {
+    if let Some(mut output_file) = open_output_file(output_file_path) {
+        write_occurences(&mut output_file, file_data, user_input, false).expect("Writing to the file failed");
     }
+}
 
-    println!("{}", file_data.file_path);
+pub fn print_watch_changes(file_path: &str, added: &[&Occurence], removed: &[&Occurence], user_input: &UserInput) {
+    let stdout = std::io::stdout();
+    let mut stdout_lock = stdout.lock();
+    let _ = write_watch_changes(&mut stdout_lock, file_path, added, removed, user_input);
+}
 
-    for occurence in occurences.iter() {
-        match occurence {
-            Token::TokenStr(ref token_str) => {
-                println!("\t{}", token_str);
-            },
-            Token::TokenStrLine(ref token_line) => {
-                println!("\t{}. {}", token_line.line_num, token_line.contents);
-            }
-        }
+pub fn write_watch_changes_to_output_file(file_path: &str, added: &[&Occurence], removed: &[&Occurence], output_file_path: &String, user_input: &UserInput) {
+    if let Some(mut output_file) = open_output_file(output_file_path) {
+        write_watch_changes(&mut output_file, file_path, added, removed, user_input).expect("Writing to the file failed");
     }
 }
 
-pub fn write_occurences_to_output_file(pattern: &String, file_data: FileData, output_file_path: &String, user_input: &UserInput) {
-    let mut output_file = match File::options().append(true).create(true).open(output_file_path) {
-        Ok(opened_file) => opened_file,
+pub fn print_stats(stats: &SearchStats, elapsed: Duration, user_input: &UserInput) {
+    let stdout = std::io::stdout();
+    let mut stdout_lock = stdout.lock();
+    let _ = write_stats(&mut stdout_lock, stats, elapsed, user_input);
+}
+
+pub fn write_stats_to_output_file(stats: &SearchStats, elapsed: Duration, output_file_path: &String, user_input: &UserInput) {
+    if let Some(mut output_file) = open_output_file(output_file_path) {
+        write_stats(&mut output_file, stats, elapsed, user_input).expect("Writing to the file failed");
+    }
+}
+
+pub fn print_replace_diff(replace_data: &FileReplaceData) {
+    let stdout = std::io::stdout();
+    let mut stdout_lock = stdout.lock();
+    let _ = write_replace_diff(&mut stdout_lock, replace_data);
+}
+
+pub fn write_replace_diff_to_output_file(replace_data: &FileReplaceData, output_file_path: &String) {
+    if let Some(mut output_file) = open_output_file(output_file_path) {
+        write_replace_diff(&mut output_file, replace_data).expect("Writing to the file failed");
+    }
+}
+
+fn open_output_file(output_file_path: &String) -> Option<File> {
+    match File::options().append(true).create(true).open(output_file_path) {
+        Ok(opened_file) => return Some(opened_file),
         Err(err) => match err.kind() {
             ErrorKind::PermissionDenied => {
                 eprintln!("File for writing output to doesn't have a permission to access\nprovided path: {}", output_file_path);
-                return ();
+                return None;
             },
             ErrorKind::IsADirectory => {
                 eprintln!("File for writing output to is a directory\nprovided path: {}", output_file_path);
-                return ();
+                return None;
             },
             _ => {
                 eprintln!("Unknown error occurred when attempting to open the file for writing program output");
                 eprintln!("Path was: {}", output_file_path);
-                return ();
+                return None;
             }
         }
     };
+}
+
+// layout of occurences: grouped under a heading with the file path, or grep's flat path:line:text;
+// a terminal gets the heading layout by default, pipes and files get the flat one
+struct OutputLayout<'a> {
+    heading:        bool,
+    with_filename:  bool,
+    // separates fields of a matched line and of a line shown for context (rest of a multiline match)
+    field_sep:      &'a str,
+    context_sep:    &'a str,
+}
+
+impl<'a> OutputLayout<'a> {
+    fn new(user_input: &'a UserInput, to_terminal: bool) -> Self {
+        let heading = if user_input.has_opt_flag(OptFlag::Heading) {
+            true
+        } else if user_input.has_opt_flag(OptFlag::NoHeading) {
+            false
+        } else {
+            to_terminal
+        };
+
+        // like grep, flat output of a single file doesn't repeat its name
+        let dir_search = user_input.has_opt_flag(OptFlag::Recursive) || user_input.has_opt_flag(OptFlag::Dir);
+        let with_filename = if user_input.has_opt_flag(OptFlag::WithFilename) {
+            true
+        } else if user_input.has_opt_flag(OptFlag::NoFilename) {
+            false
+        } else {
+            heading || dir_search
+        };
 
-    let ignore_case = user_input.has_opt_flag(OptFlag::IgnoreCase);
+        Self {
+            heading,
+            with_filename,
+            field_sep:      user_input.field_separator.as_deref().unwrap_or(":"),
+            context_sep:    user_input.context_separator.as_deref().unwrap_or("-"),
+        }
+    }
+}
 
-    let occurences = str_pattern_match::find_occurences(file_data.file_tokens, &pattern, ignore_case);
-    if occurences.len() == 0 {
-        return;
+fn write_occurences<W: Write>(out: &mut W, file_data: &FileData, user_input: &UserInput, to_terminal: bool) -> std::io::Result<()> {
+    if file_data.occurences.is_empty() {
+        return Ok(());
     }
 
-    let _ = output_file.write(file_data.file_path.as_bytes()).expect("Writing to the file failed");
-    let _ = output_file.write(b"\n");
+    // file list for xargs, -0 separates paths by NUL as they may contain newlines
+    if user_input.has_opt_flag(OptFlag::FilesWithMatches) {
+        let terminator = if user_input.has_opt_flag(OptFlag::Null) { '\0' } else { '\n' };
+        return write!(out, "{}{}", file_data.file_path, terminator);
+    }
 
-    for occurence in occurences.iter() {
-        match occurence {
-            Token::TokenStr(ref token_str) => {
-                let _ = output_file.write(b"\t");
-                let _ = output_file.write(token_str.as_bytes()).expect("Writing to the file failed");
-                let _ = output_file.write(b"\n");
-            },
-            Token::TokenStrLine(ref token_line) => {
-                let _ = output_file.write(b"\t");
-                let line_n_to_print: Vec<u8> = token_line.line_num.to_string().bytes().collect();
-                let _ = output_file.write(&line_n_to_print[..]);
-                let _ = output_file.write(b". ");
-                let _ = output_file.write(token_line.contents.as_bytes()).expect("Writing to the file failed");
-                let _ = output_file.write(b"\n");
+    if user_input.has_opt_flag(OptFlag::Vimgrep) {
+        for occurence in file_data.occurences.iter() {
+            write_vimgrep_occurence(out, &file_data.file_path, occurence)?;
+        }
+        return Ok(());
+    }
+
+    let only_matching = user_input.has_opt_flag(OptFlag::OnlyMatching);
+    let column = user_input.has_opt_flag(OptFlag::Column);
+    let layout = OutputLayout::new(user_input, to_terminal);
+
+    if !layout.heading {
+        for occurence in file_data.occurences.iter() {
+            write_flat_occurence(out, &file_data.file_path, occurence, &layout, only_matching, column)?;
+        }
+        return Ok(());
+    }
+
+    if layout.with_filename {
+        writeln!(out, "{}", file_data.file_path)?;
+    }
+    for occurence in file_data.occurences.iter() {
+        write_occurence(out, occurence, "", only_matching, column)?;
+    }
+
+    return Ok(());
+}
+
+// every line is prefixed with the path and the line number
+fn write_flat_occurence<W: Write>(out: &mut W, file_path: &str, occurence: &Occurence, layout: &OutputLayout, only_matching: bool, column: bool) -> std::io::Result<()> {
+    let contents = occurence.token.contents();
+    let line_num = match occurence.token {
+        Token::TokenStr(_) => None,
+        Token::TokenStrLine(ref token_line) => Some(token_line.line_num + 1),
+    };
+
+    if !only_matching {
+        for (line_offset, line) in contents.split('\n').enumerate() {
+            let line = line.trim_end_matches('\r');
+            // lines after the first one of a multiline match are context, as in grep
+            let sep = if line_offset == 0 { layout.field_sep } else { layout.context_sep };
+
+            if layout.with_filename {
+                write!(out, "{}{}", file_path, sep)?;
+            }
+            if let Some(line_num) = line_num {
+                write!(out, "{}{}", line_num + line_offset, sep)?;
+            }
+            write!(out, "{}", line)?;
+
+            match occurence.best_distance() {
+                Some(distance) if line_offset == 0 => writeln!(out, " (distance: {})", distance)?,
+                _ => writeln!(out)?,
+            }
+        }
+        return Ok(());
+    }
+
+    for (match_ind, found) in occurence.matches.iter().enumerate() {
+        // empty matches (of x* and the like) have nothing to show, grep skips them too
+        if found.start == found.end {
+            continue;
+        }
+        if layout.with_filename {
+            write!(out, "{}{}", file_path, layout.field_sep)?;
+        }
+        if let Some(line_num) = line_num {
+            write!(out, "{}{}", line_num, layout.field_sep)?;
+        }
+        if column {
+            // 1-based column in chars, not bytes
+            write!(out, "{}{}", contents[..found.start].chars().count() + 1, layout.field_sep)?;
+        }
+        write!(out, "{}", &contents[found.start..found.end])?;
+
+        match occurence.distances.get(match_ind) {
+            Some(distance) => writeln!(out, " (distance: {})", distance)?,
+            None => writeln!(out)?,
+        }
+    }
+
+    return Ok(());
+}
+
+// path:line:column:text for every match, the format of `:set grepformat=%f:%l:%c:%m` and of compilers,
+// so editors can jump to matches; columns are in bytes, as Vim expects
+fn write_vimgrep_occurence<W: Write>(out: &mut W, file_path: &str, occurence: &Occurence) -> std::io::Result<()> {
+    let contents = occurence.token.contents();
+    let line_num = match occurence.token {
+        Token::TokenStr(_) => 0,
+        Token::TokenStrLine(ref token_line) => token_line.line_num,
+    };
+
+    for found in occurence.matches.iter() {
+        // a multiline match is reported at the line it starts on
+        let line_start = contents[..found.start].rfind('\n').map_or(0, |ind| ind + 1);
+        let line_offset = contents[..line_start].matches('\n').count();
+        let line_end = contents[line_start..].find('\n').map_or(contents.len(), |ind| line_start + ind);
+        let line = contents[line_start..line_end].trim_end_matches('\r');
+
+        writeln!(out, "{}:{}:{}:{}", file_path, line_num + line_offset + 1, found.start - line_start + 1, line)?;
+    }
+
+    return Ok(());
+}
+
+// matches which appeared and disappeared in a watched file, marked with + and -
+fn write_watch_changes<W: Write>(out: &mut W, file_path: &str, added: &[&Occurence], removed: &[&Occurence], user_input: &UserInput) -> std::io::Result<()> {
+    if added.is_empty() && removed.is_empty() {
+        return Ok(());
+    }
+
+    let only_matching = user_input.has_opt_flag(OptFlag::OnlyMatching);
+    let column = user_input.has_opt_flag(OptFlag::Column);
+
+    writeln!(out, "{}", file_path)?;
+    for occurence in removed.iter() {
+        write_occurence(out, occurence, "- ", only_matching, column)?;
+    }
+    for occurence in added.iter() {
+        write_occurence(out, occurence, "+ ", only_matching, column)?;
+    }
+
+    return Ok(());
+}
+
+fn write_stats<W: Write>(out: &mut W, stats: &SearchStats, elapsed: Duration, user_input: &UserInput) -> std::io::Result<()> {
+    let files_skipped = stats.paths_excluded + stats.files_filtered + stats.files_binary + stats.paths_denied + stats.files_failed;
+
+    // keys and their order are kept stable, scripts depend on them
+    if user_input.has_opt_flag(OptFlag::StatsJson) {
+        writeln!(
+            out,
+            "{{\"files_searched\":{},\"files_matched\":{},\"files_skipped\":{{\"excluded\":{},\"filtered\":{},\"binary\":{},\"permission_denied\":{},\"other_errors\":{}}},\"matches\":{},\"bytes_read\":{},\"elapsed_secs\":{:.6}}}",
+            stats.files_searched, stats.files_matched,
+            stats.paths_excluded, stats.files_filtered, stats.files_binary, stats.paths_denied, stats.files_failed,
+            stats.matches, stats.bytes_read, elapsed.as_secs_f64(),
+        )?;
+        return Ok(());
+    }
+
+    writeln!(out)?;
+    writeln!(out, "{} matches", stats.matches)?;
+    writeln!(out, "{} files matched", stats.files_matched)?;
+    writeln!(out, "{} files searched", stats.files_searched)?;
+    writeln!(
+        out,
+        "{} skipped: {} excluded, {} filtered, {} binary, {} permission denied, {} other errors",
+        files_skipped, stats.paths_excluded, stats.files_filtered, stats.files_binary, stats.paths_denied, stats.files_failed,
+    )?;
+    writeln!(out, "{} bytes read", stats.bytes_read)?;
+    writeln!(out, "{:.6} seconds elapsed", elapsed.as_secs_f64())?;
+
+    return Ok(());
+}
+
+fn write_occurence<W: Write>(out: &mut W, occurence: &Occurence, marker: &str, only_matching: bool, column: bool) -> std::io::Result<()> {
+    let contents = occurence.token.contents();
+    let line_num = match occurence.token {
+        Token::TokenStr(_) => None,
+        // line numbers are 1-based in every layout, as in grep
+        Token::TokenStrLine(ref token_line) => Some(token_line.line_num + 1),
+    };
+
+    if !only_matching {
+        // multiline matches span a few lines, each one is numbered
+        for (line_offset, line) in contents.split('\n').enumerate() {
+            let line = line.trim_end_matches('\r');
+            match line_num {
+                Some(line_num) => write!(out, "\t{}{}. {}", marker, line_num + line_offset, line)?,
+                None => write!(out, "\t{}{}", marker, line)?,
+            }
+            // closeness of approximate matches is shown once, on the first line
+            match occurence.best_distance() {
+                Some(distance) if line_offset == 0 => writeln!(out, " (distance: {})", distance)?,
+                _ => writeln!(out)?,
+            }
+        }
+        return Ok(());
+    }
+
+    // every match on its own line
+    for (match_ind, found) in occurence.matches.iter().enumerate() {
+        // empty matches (of x* and the like) have nothing to show, grep skips them too
+        if found.start == found.end {
+            continue;
+        }
+        let matched = &contents[found.start..found.end];
+        // 1-based column in chars, not bytes
+        let col_num = contents[..found.start].chars().count() + 1;
+
+        match (line_num, column) {
+            (Some(line_num), true) => write!(out, "\t{}{}:{}. {}", marker, line_num, col_num, matched)?,
+            (Some(line_num), false) => write!(out, "\t{}{}. {}", marker, line_num, matched)?,
+            (None, true) => write!(out, "\t{}{}. {}", marker, col_num, matched)?,
+            (None, false) => write!(out, "\t{}{}", marker, matched)?,
+        }
+        match occurence.distances.get(match_ind) {
+            Some(distance) => writeln!(out, " (distance: {})", distance)?,
+            None => writeln!(out)?,
+        }
+    }
+
+    return Ok(());
+}
+
+// unified diff between old and new contents of a file, `patch -p0` can apply it
+fn write_replace_diff<W: Write>(out: &mut W, replace_data: &FileReplaceData) -> std::io::Result<()> {
+    let old_lines = &replace_data.old_lines;
+    let new_lines = &replace_data.new_lines;
+    let changed: Vec<usize> = (0..old_lines.len()).filter(|ind| old_lines[*ind] != new_lines[*ind]).collect();
+    if changed.is_empty() {
+        return Ok(());
+    }
+
+    writeln!(out, "--- {}", replace_data.file_path)?;
+    writeln!(out, "+++ {}", replace_data.file_path)?;
+
+    // how much new file is longer than the old one before the current hunk
+    let mut new_lines_offset: isize = 0;
+    let mut changed_ind: usize = 0;
+
+    while changed_ind < changed.len() {
+        let hunk_start = changed[changed_ind].saturating_sub(DIFF_CONTEXT_LINES);
+        let mut hunk_last_changed = changed[changed_ind];
+
+        // changes which are close enough share the context and go into one hunk
+        changed_ind += 1;
+        while changed_ind < changed.len() && changed[changed_ind] - hunk_last_changed <= 2 * DIFF_CONTEXT_LINES {
+            hunk_last_changed = changed[changed_ind];
+            changed_ind += 1;
+        }
+        let hunk_end = (hunk_last_changed + DIFF_CONTEXT_LINES + 1).min(old_lines.len());
+
+        let mut hunk_body = Vec::<u8>::new();
+        let mut old_count: usize = 0;
+        let mut new_count: usize = 0;
+
+        for ind in hunk_start..hunk_end {
+            if old_lines[ind] == new_lines[ind] {
+                write_diff_line(&mut hunk_body, ' ', &old_lines[ind])?;
+                old_count += 1;
+                new_count += 1;
+                continue;
+            }
+
+            write_diff_line(&mut hunk_body, '-', &old_lines[ind])?;
+            old_count += 1;
+            for new_line in new_lines[ind].split_inclusive('\n') {
+                write_diff_line(&mut hunk_body, '+', new_line)?;
+                new_count += 1;
             }
         }
+
+        let new_start = (hunk_start as isize + new_lines_offset) as usize;
+        writeln!(out, "@@ -{} +{} @@", diff_range(hunk_start, old_count), diff_range(new_start, new_count))?;
+        out.write_all(&hunk_body)?;
+
+        new_lines_offset += new_count as isize - old_count as isize;
+    }
+
+    return Ok(());
+}
+
+fn write_diff_line<W: Write>(out: &mut W, prefix: char, line: &str) -> std::io::Result<()> {
+    write!(out, "{}{}", prefix, line)?;
+    if !line.ends_with('\n') {
+        writeln!(out)?;
+        writeln!(out, "\\ No newline at end of file")?;
     }
+
+    return Ok(());
+}
+
+// range of a hunk in unified diff notation, `start` is 0-based index of the first line
+fn diff_range(start: usize, count: usize) -> String {
+    if count == 0 {
+        // empty range points to the line before it
+        return format!("{},0", start);
+    }
+
+    return format!("{},{}", start + 1, count);
 }
@@ -0,0 +1,247 @@
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+
+use bzip2::read::MultiBzDecoder;
+use flate2::read::MultiGzDecoder;
+use ruzstd::decoding::StreamingDecoder;
+
+const MAGIC_GZIP: &[u8]     = &[0x1F, 0x8B];
+const MAGIC_BZIP2: &[u8]    = b"BZh";
+// after the magic and the block size digit a stream starts with a block, or ends right away if it's empty
+const BZIP2_BLOCK_MAGIC: &[u8] = &[0x31, 0x41, 0x59, 0x26, 0x53, 0x59];
+const BZIP2_END_MAGIC: &[u8]   = &[0x17, 0x72, 0x45, 0x38, 0x50, 0x90];
+const MAGIC_XZ: &[u8]       = &[0xFD, b'7', b'z', b'X', b'Z', 0x00];
+const MAGIC_ZSTD: &[u8]     = &[0x28, 0xB5, 0x2F, 0xFD];
+
+// decoded chunks of xz data which may wait for the reader
+const XZ_CHANNEL_CHUNKS: usize = 16;
+const XZ_CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(PartialEq, Clone, Copy)]
+pub enum Compression {
+    Gzip,
+    Bzip2,
+    Xz,
+    Zstd,
+}
+
+// compression format recognized by magic bytes at the start of `header`
+pub fn detect_compression(header: &[u8]) -> Option<Compression> {
+    if header.starts_with(MAGIC_GZIP) {
+        return Some(Compression::Gzip);
+    }
+    if is_bzip2_header(header) {
+        return Some(Compression::Bzip2);
+    }
+    if header.starts_with(MAGIC_XZ) {
+        return Some(Compression::Xz);
+    }
+    if header.starts_with(MAGIC_ZSTD) {
+        return Some(Compression::Zstd);
+    }
+
+    return None;
+}
+
+// "BZh" alone is too common at the start of a text file, so the block size and the first block are checked too
+fn is_bzip2_header(header: &[u8]) -> bool {
+    if !header.starts_with(MAGIC_BZIP2) {
+        return false;
+    }
+
+    let block_size = header.get(MAGIC_BZIP2.len());
+    if !block_size.is_some_and(|digit| (b'1'..=b'9').contains(digit)) {
+        return false;
+    }
+
+    let rest = &header[MAGIC_BZIP2.len() + 1..];
+    return rest.starts_with(BZIP2_BLOCK_MAGIC) || rest.starts_with(BZIP2_END_MAGIC);
+}
+
+// returns a reader of decompressed data if `reader` is compressed, otherwise `reader` itself;
+// data is decompressed while it's read, so it's never stored in memory as a whole
+pub fn decompressing_reader<R: BufRead + Send + 'static>(mut reader: R) -> std::io::Result<Box<dyn BufRead + Send>> {
+    let compression = match detect_compression(reader.fill_buf()?) {
+        Some(compression) => compression,
+        None => return Ok(Box::new(reader)),
+    };
+
+    match compression {
+        // concatenated members are common for rotated logs, all of them are read
+        Compression::Gzip => return Ok(Box::new(BufReader::new(MultiGzDecoder::new(reader)))),
+        Compression::Bzip2 => return Ok(Box::new(BufReader::new(MultiBzDecoder::new(reader)))),
+        Compression::Zstd => {
+            let decoder = StreamingDecoder::new(reader)
+                .map_err(|err| std::io::Error::new(ErrorKind::InvalidData, err.to_string()))?;
+            return Ok(Box::new(BufReader::new(decoder)));
+        },
+        Compression::Xz => return xz_reader(reader),
+    }
+}
+
+// lzma-rs only decodes xz from a reader into a writer, so decoding runs in a separate thread
+// sending decoded chunks through a bounded channel; when the reader is dropped early,
+// sending fails and the thread stops
+fn xz_reader<R: BufRead + Send + 'static>(mut reader: R) -> std::io::Result<Box<dyn BufRead + Send>> {
+    let (sender, receiver) = mpsc::sync_channel::<std::io::Result<Vec<u8>>>(XZ_CHANNEL_CHUNKS);
+
+    std::thread::spawn(move || {
+        // decoder writes in small pieces, they are batched to not send a message per every few bytes
+        let mut chunk_writer = BufWriter::with_capacity(XZ_CHUNK_SIZE, ChunkWriter { sender: sender.clone() });
+        let result = lzma_rs::xz_decompress(&mut reader, &mut chunk_writer);
+        match result {
+            Ok(_) => {
+                let _ = chunk_writer.flush();
+            },
+            Err(err) => {
+                let _ = sender.send(Err(std::io::Error::new(ErrorKind::InvalidData, format!("xz decompression failed: {:?}", err))));
+            }
+        }
+    });
+
+    let chunk_reader = ChunkReader {
+        receiver,
+        chunk: Vec::new(),
+        chunk_pos: 0,
+    };
+    return Ok(Box::new(BufReader::new(chunk_reader)));
+}
+
+struct ChunkWriter {
+    sender: SyncSender<std::io::Result<Vec<u8>>>,
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self.sender.send(Ok(buf.to_vec())) {
+            Ok(_) => return Ok(buf.len()),
+            Err(_) => return Err(std::io::Error::new(ErrorKind::BrokenPipe, "reader of decompressed data is closed")),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        return Ok(());
+    }
+}
+
+struct ChunkReader {
+    receiver:   Receiver<std::io::Result<Vec<u8>>>,
+    chunk:      Vec<u8>,
+    chunk_pos:  usize,
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.chunk_pos == self.chunk.len() {
+            match self.receiver.recv() {
+                Ok(Ok(chunk)) => {
+                    self.chunk = chunk;
+                    self.chunk_pos = 0;
+                },
+                Ok(Err(err)) => return Err(err),
+                // decoding thread is done
+                Err(_) => return Ok(0),
+            }
+        }
+
+        let available = &self.chunk[self.chunk_pos..];
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.chunk_pos += len;
+
+        return Ok(len);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const TEXT: &str = "first line\nвторая строка\n";
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        return encoder.finish().unwrap();
+    }
+
+    fn bzip2(data: &[u8]) -> Vec<u8> {
+        let mut encoder = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::default());
+        encoder.write_all(data).unwrap();
+        return encoder.finish().unwrap();
+    }
+
+    fn xz(data: &[u8]) -> Vec<u8> {
+        let mut compressed = Vec::new();
+        lzma_rs::xz_compress(&mut Cursor::new(data), &mut compressed).unwrap();
+        return compressed;
+    }
+
+    fn zstd(data: &[u8]) -> Vec<u8> {
+        return ruzstd::encoding::compress_to_vec(data, ruzstd::encoding::CompressionLevel::Fastest);
+    }
+
+    fn decompress(data: Vec<u8>) -> std::io::Result<Vec<u8>> {
+        let mut reader = decompressing_reader(Cursor::new(data))?;
+        let mut decompressed = Vec::new();
+        reader.read_to_end(&mut decompressed)?;
+        return Ok(decompressed);
+    }
+
+    #[test]
+    fn detects_compression_by_magic_bytes() {
+        assert!(detect_compression(&gzip(TEXT.as_bytes())) == Some(Compression::Gzip));
+        assert!(detect_compression(&bzip2(TEXT.as_bytes())) == Some(Compression::Bzip2));
+        assert!(detect_compression(&xz(TEXT.as_bytes())) == Some(Compression::Xz));
+        assert!(detect_compression(&zstd(TEXT.as_bytes())) == Some(Compression::Zstd));
+        assert!(detect_compression(TEXT.as_bytes()).is_none());
+        assert!(detect_compression(b"").is_none());
+    }
+
+    #[test]
+    fn bzip2_header_needs_block_size_and_block_magic() {
+        assert!(is_bzip2_header(&bzip2(TEXT.as_bytes())));
+        // a stream without data has no blocks, only the end of stream
+        assert!(is_bzip2_header(&bzip2(b"")));
+        assert!(!is_bzip2_header(b"BZhello"));
+        assert!(!is_bzip2_header(b"BZh9 is a text"));
+        assert!(!is_bzip2_header(b"BZh0\x31\x41\x59\x26\x53\x59"));
+        assert!(!is_bzip2_header(b"BZh9\x31\x41\x59"));
+        assert!(!is_bzip2_header(b"BZh"));
+    }
+
+    #[test]
+    fn decompresses_every_format() {
+        for compress in [gzip, bzip2, xz, zstd] {
+            assert_eq!(decompress(compress(TEXT.as_bytes())).unwrap(), TEXT.as_bytes());
+        }
+        // xz is decoded in chunks by another thread
+        let long_text = TEXT.repeat(20_000);
+        assert_eq!(decompress(xz(long_text.as_bytes())).unwrap(), long_text.as_bytes());
+    }
+
+    #[test]
+    fn reads_concatenated_members() {
+        for compress in [gzip, bzip2] {
+            let mut data = compress(b"one\n");
+            data.extend(compress(b"two\n"));
+            assert_eq!(decompress(data).unwrap(), b"one\ntwo\n");
+        }
+    }
+
+    #[test]
+    fn passes_uncompressed_data_through() {
+        assert_eq!(decompress(TEXT.as_bytes().to_vec()).unwrap(), TEXT.as_bytes());
+        assert_eq!(decompress(b"BZhello".to_vec()).unwrap(), b"BZhello");
+    }
+
+    #[test]
+    fn fails_on_corrupted_data() {
+        for compress in [gzip, xz] {
+            let mut data = compress(TEXT.as_bytes());
+            data.truncate(data.len() / 2);
+            assert!(decompress(data).is_err());
+        }
+    }
+}
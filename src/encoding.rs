@@ -0,0 +1,205 @@
+use std::io::{BufRead, BufReader, ErrorKind, Read};
+
+use encoding_rs::{CoderResult, Decoder, DecoderResult, Encoding, UTF_8};
+
+const DECODE_BUFF_SIZE: usize = 8 * 1024;
+
+// returns a reader which yields utf-8 text of `reader`:
+// text is transcoded if `encoding` is provided or if it starts with a byte order mark (utf-8, utf-16le/be),
+// with `lossy` invalid bytes are replaced with U+FFFD instead of failing the read
+pub fn utf8_reader<R: BufRead + 'static>(mut reader: R, encoding: Option<&'static Encoding>, lossy: bool) -> std::io::Result<Box<dyn BufRead>> {
+    let encoding = match encoding {
+        Some(encoding) => Some(encoding),
+        None => detect_bom(reader.fill_buf()?),
+    };
+
+    // plain utf-8 is read as is, it's validated later when lines are read
+    if encoding.is_none() && !lossy {
+        return Ok(Box::new(reader));
+    }
+
+    let decoding_reader = DecodingReader::new(reader, encoding.unwrap_or(UTF_8), lossy);
+    return Ok(Box::new(BufReader::new(decoding_reader)));
+}
+
+// encoding which byte order mark is at the start of `bytes`, if any
+pub fn detect_bom(bytes: &[u8]) -> Option<&'static Encoding> {
+    return Encoding::for_bom(bytes).map(|(encoding, _bom_len)| encoding);
+}
+
+// transcodes bytes of `inner` in some encoding into utf-8 on the fly
+pub struct DecodingReader<R: Read> {
+    inner:      R,
+    decoder:    Decoder,
+    lossy:      bool,
+    in_buff:    Vec<u8>,
+    in_start:   usize,
+    in_end:     usize,
+    in_eof:     bool,
+    out_buff:   Vec<u8>,
+    out_start:  usize,
+    out_end:    usize,
+    finished:   bool,
+}
+
+impl<R: Read> DecodingReader<R> {
+    pub fn new(inner: R, encoding: &'static Encoding, lossy: bool) -> Self {
+        Self {
+            inner,
+            // byte order mark, if present, wins over the provided encoding and is not a part of the text
+            decoder:    encoding.new_decoder(),
+            lossy,
+            in_buff:    vec![0; DECODE_BUFF_SIZE],
+            in_start:   0,
+            in_end:     0,
+            in_eof:     false,
+            // utf-8 may take up to 3 bytes for a single byte or 2 byte code unit of the source
+            out_buff:   vec![0; DECODE_BUFF_SIZE * 3],
+            out_start:  0,
+            out_end:    0,
+            finished:   false,
+        }
+    }
+
+    fn fill_out_buff(&mut self) -> std::io::Result<()> {
+        while !self.finished {
+            if self.in_start == self.in_end && !self.in_eof {
+                let read = self.inner.read(&mut self.in_buff)?;
+                self.in_start = 0;
+                self.in_end = read;
+                self.in_eof = read == 0;
+            }
+
+            let src = &self.in_buff[self.in_start..self.in_end];
+            let last = self.in_eof;
+
+            let (read, written, done) = if self.lossy {
+                let (result, read, written, _had_replacements) = self.decoder.decode_to_utf8(src, &mut self.out_buff, last);
+                (read, written, result == CoderResult::InputEmpty)
+            } else {
+                let (result, read, written) = self.decoder.decode_to_utf8_without_replacement(src, &mut self.out_buff, last);
+                if let DecoderResult::Malformed(_, _) = result {
+                    let encoding_name = self.decoder.encoding().name();
+                    return Err(std::io::Error::new(ErrorKind::InvalidData, format!("stream did not contain valid {}", encoding_name)));
+                }
+                (read, written, result == DecoderResult::InputEmpty)
+            };
+
+            self.in_start += read;
+            self.out_start = 0;
+            self.out_end = written;
+
+            if last && done {
+                self.finished = true;
+            }
+            if written > 0 {
+                break;
+            }
+        }
+
+        return Ok(());
+    }
+}
+
+impl<R: Read> Read for DecodingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.out_start == self.out_end {
+            if self.finished {
+                return Ok(0);
+            }
+            self.fill_out_buff()?;
+        }
+
+        let available = &self.out_buff[self.out_start..self.out_end];
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.out_start += len;
+
+        return Ok(len);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use encoding_rs::{UTF_16BE, UTF_16LE};
+
+    // gives out at most one byte per read, so every multibyte sequence is split between reads
+    struct ByteByByte(Cursor<Vec<u8>>);
+
+    impl Read for ByteByByte {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let len = buf.len().min(1);
+            return self.0.read(&mut buf[..len]);
+        }
+    }
+
+    fn utf16(text: &str, big_endian: bool) -> Vec<u8> {
+        return text.encode_utf16()
+            .flat_map(|unit| if big_endian { unit.to_be_bytes() } else { unit.to_le_bytes() })
+            .collect();
+    }
+
+    fn read_text<R: Read>(mut reader: R) -> std::io::Result<String> {
+        let mut text = String::new();
+        reader.read_to_string(&mut text)?;
+        return Ok(text);
+    }
+
+    #[test]
+    fn detects_byte_order_marks() {
+        assert_eq!(detect_bom(b"\xEF\xBB\xBFtext"), Some(UTF_8));
+        assert_eq!(detect_bom(b"\xFF\xFEt\x00"), Some(UTF_16LE));
+        assert_eq!(detect_bom(b"\xFE\xFF\x00t"), Some(UTF_16BE));
+        assert_eq!(detect_bom(b"text"), None);
+        assert_eq!(detect_bom(b"\xFF"), None);
+        assert_eq!(detect_bom(b""), None);
+    }
+
+    #[test]
+    fn decodes_utf16_with_byte_order_mark() {
+        let text = "привет, 世界 🦀\nline\n";
+        for big_endian in [false, true] {
+            let mut bytes = utf16("\u{FEFF}", big_endian);
+            bytes.extend(utf16(text, big_endian));
+            let reader = utf8_reader(Cursor::new(bytes), None, false).unwrap();
+            assert_eq!(read_text(reader).unwrap(), text);
+        }
+    }
+
+    #[test]
+    fn decodes_provided_encoding_without_byte_order_mark() {
+        let reader = utf8_reader(Cursor::new(utf16("ab ц", false)), Some(UTF_16LE), false).unwrap();
+        assert_eq!(read_text(reader).unwrap(), "ab ц");
+        // utf-8 text without a mark is passed through as is
+        let reader = utf8_reader(Cursor::new("ab ц".as_bytes().to_vec()), None, false).unwrap();
+        assert_eq!(read_text(reader).unwrap(), "ab ц");
+    }
+
+    #[test]
+    fn decodes_sequences_split_between_reads() {
+        let text = "ж€🦀 ab";
+        let reader = DecodingReader::new(ByteByByte(Cursor::new(text.as_bytes().to_vec())), UTF_8, true);
+        assert_eq!(read_text(reader).unwrap(), text);
+        let reader = DecodingReader::new(ByteByByte(Cursor::new(utf16(text, true))), UTF_16BE, false);
+        assert_eq!(read_text(reader).unwrap(), text);
+
+        // a char across the end of the input buffer
+        let text = format!("{}ж{}🦀", "a".repeat(DECODE_BUFF_SIZE - 1), "b".repeat(DECODE_BUFF_SIZE - 2));
+        let reader = DecodingReader::new(Cursor::new(text.as_bytes().to_vec()), UTF_8, false);
+        assert_eq!(read_text(reader).unwrap(), text);
+    }
+
+    #[test]
+    fn invalid_bytes_fail_unless_lossy() {
+        let bytes = b"ok \xFF\xFE\xFD end".to_vec();
+        let reader = DecodingReader::new(Cursor::new(bytes.clone()), UTF_8, false);
+        assert_eq!(read_text(reader).unwrap_err().kind(), ErrorKind::InvalidData);
+        let reader = DecodingReader::new(ByteByByte(Cursor::new(bytes)), UTF_8, true);
+        assert_eq!(read_text(reader).unwrap(), "ok \u{FFFD}\u{FFFD}\u{FFFD} end");
+        // a sequence cut by the end of the input
+        let reader = DecodingReader::new(Cursor::new(b"ab\xD0".to_vec()), UTF_8, true);
+        assert_eq!(read_text(reader).unwrap(), "ab\u{FFFD}");
+    }
+}
@@ -1,344 +1,1637 @@
-use std::fs::{File, FileType};
-use std::io::{ErrorKind, Read};
-use std::str::FromStr;
+use std::cell::RefCell;
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::HashSet;
+use std::fs::{DirEntry, File, FileType, Metadata};
+use std::io::{BufRead, BufReader, Cursor, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime};
+use encoding_rs::Encoding;
+use memchr::{memchr, memchr_iter, memrchr};
+use memmap2::Mmap;
+
+use crate::archive::{archive_kind, for_each_member, member_path, ArchiveKind};
 use crate::cli_input::{UserInput, OptFlag};
+use crate::decompress::{decompressing_reader, detect_compression};
+use crate::encoding::{detect_bom, utf8_reader};
+use crate::glob::glob_match_any;
+use crate::index::{TrigramIndex, TrigramQuery, TrigramSet, INDEX_FILE_NAME};
+use crate::preprocess::preprocessor_reader;
+use crate::query::QueryScope;
+use crate::str_pattern_match::{decode_utf8_char, find_iter, replace_all, utf8_char_width, Match, Matcher, Occurence};
+
+// files at least this big are memory mapped instead of being read through a buffer
+const MMAP_MIN_FILE_SIZE: u64 = 8 * 1024 * 1024;
+// how often a followed file is checked for appended data
+const FOLLOW_POLL_INTERVAL: Duration = Duration::from_millis(200);
 
 pub struct TokenWithLine {
     pub contents: String,
     pub line_num: usize,
 }
 
-pub enum Token {
-    TokenStr(String),
-    TokenStrLine(TokenWithLine),
-}
+pub enum Token {
+    TokenStr(String),
+    TokenStrLine(TokenWithLine),
+}
+
+impl Token {
+    pub fn contents(&self) -> &str {
+        return match self {
+            Token::TokenStr(token_str) => token_str,
+            Token::TokenStrLine(token_line) => &token_line.contents,
+        };
+    }
+}
+
+// file with at least one match, only matched tokens are kept
+pub struct FileData {
+    pub file_path:          String,
+    pub occurences:         Vec<Occurence>,
+}
+
+// file with at least one replacement, `new_lines[i]` is the replaced `old_lines[i]`,
+// lines keep their terminators and a replaced line may turn into a few lines
+pub struct FileReplaceData {
+    pub file_path:          String,
+    pub old_lines:          Vec<String>,
+    pub new_lines:          Vec<String>,
+    pub replacements:       usize,
+}
+
+impl FileReplaceData {
+    pub fn new_contents(&self) -> String {
+        return self.new_lines.concat();
+    }
+}
+
+// what results are sorted by, with --sort or --sortr
+#[derive(Clone, Copy, PartialEq)]
+pub enum SortKey {
+    Path,
+    Modified,
+    Created,
+    Size,
+    Matches,
+}
+
+// counters of a search, for --stats
+#[derive(Clone, Default)]
+pub struct SearchStats {
+    pub files_searched:     usize,
+    pub files_matched:      usize,
+    // paths skipped by -e, a skipped directory counts once
+    pub paths_excluded:     usize,
+    // files skipped by size, time, owner or permission filters
+    pub files_filtered:     usize,
+    // files which are not text in the expected encoding
+    pub files_binary:       usize,
+    // files and directories which couldn't be accessed
+    pub paths_denied:       usize,
+    pub files_failed:       usize,
+    pub matches:            usize,
+    // bytes of text read from files, after decompression and decoding
+    pub bytes_read:         u64,
+}
+
+// options of a single search, collected from user input once
+struct SearchOpts<'a> {
+    quiet:              bool,
+    line_numbers:       bool,
+    // tokens are whole lines instead of whitespace separated words
+    line_tokens:        bool,
+    exclude_paths:      Option<&'a Vec<String>>,
+    max_count:          Option<usize>,
+    max_total:          Option<usize>,
+    // encoding of files, detected by byte order mark if not provided
+    encoding:           Option<&'static Encoding>,
+    // replace invalid bytes instead of skipping the file
+    lossy:              bool,
+    // read compressed files as their decompressed contents
+    decompress:         bool,
+    // search members of tar and zip archives instead of archives themselves
+    archives:           bool,
+    // command which output is searched instead of contents of files matching `preprocessor_globs`
+    preprocessor:       Option<&'a String>,
+    preprocessor_globs: Option<&'a Vec<String>>,
+    // globs with '/' are matched against paths relative to it, like .gitignore patterns
+    search_root:        &'a str,
+    // lines without a match are skipped before splitting them into tokens,
+    // not the case for regexes searched in words, as anchors may match at start of a word but not of a line
+    prefilter_lines:    bool,
+    // pattern is matched against the whole file, a match may span a few lines
+    multiline:          bool,
+    // query is evaluated over whole files, so files are read whole before searching lines
+    file_query:         bool,
+    // options are passed everywhere during a search, so counters are kept with them
+    stats:              RefCell<SearchStats>,
+}
+
+impl<'a> SearchOpts<'a> {
+    fn from_user_input(user_input: &'a UserInput) -> Self {
+        // a query is evaluated over the whole line, a NOT term must not match in any word of it
+        let line_query = user_input.query.is_some() && user_input.query_scope == QueryScope::Line;
+        // vimgrep format shows the whole line of every match
+        let vimgrep = user_input.has_opt_flag(OptFlag::Vimgrep);
+        let line_tokens = user_input.has_opt_flag(OptFlag::WholeLine) || user_input.has_opt_flag(OptFlag::OnlyMatching) || line_query || vimgrep;
+        // a listed file needs only one match, unless files are ordered by their matches
+        let list_files = user_input.has_opt_flag(OptFlag::FilesWithMatches) && user_input.sort_by != Some(SortKey::Matches);
+        let max_count = match list_files {
+            true => Some(1),
+            false => user_input.max_count,
+        };
+
+        Self {
+            quiet:              user_input.has_opt_flag(OptFlag::Quiet),
+            line_numbers:       user_input.has_opt_flag(OptFlag::LineNumbers) || vimgrep,
+            line_tokens,
+            exclude_paths:      user_input.exclude_paths.as_ref(),
+            max_count,
+            max_total:          if user_input.has_opt_flag(OptFlag::QuitOnMatch) { Some(1) } else { user_input.max_total },
+            encoding:           user_input.encoding,
+            lossy:              user_input.has_opt_flag(OptFlag::Lossy),
+            decompress:         user_input.has_opt_flag(OptFlag::Decompress),
+            archives:           user_input.has_opt_flag(OptFlag::Archives),
+            preprocessor:       user_input.preprocessor.as_ref(),
+            preprocessor_globs: user_input.preprocessor_globs.as_ref(),
+            search_root:        &user_input.search_path,
+            prefilter_lines:    !line_tokens && !user_input.has_opt_flag(OptFlag::Regex),
+            multiline:          user_input.has_opt_flag(OptFlag::Multiline),
+            file_query:         user_input.query.is_some() && user_input.query_scope == QueryScope::File,
+            stats:              RefCell::new(SearchStats::default()),
+        }
+    }
+
+    // without globs every file goes through the preprocessor
+    fn preprocessor_for(&self, file_path: &str) -> Option<&'a String> {
+        let command = self.preprocessor?;
+        match self.preprocessor_globs {
+            Some(globs) if !glob_match_any(globs, &relative_path(file_path, self.search_root)) => return None,
+            _ => return Some(command),
+        }
+    }
+
+    // matches allowed in the next file by both per-file and global limits
+    fn file_limit(&self, total_matches: usize) -> Option<usize> {
+        let remaining_total = self.max_total.map(|max_total| max_total.saturating_sub(total_matches));
+        match (self.max_count, remaining_total) {
+            (Some(max_count), Some(remaining)) => return Some(max_count.min(remaining)),
+            (max_count, remaining) => return max_count.or(remaining),
+        }
+    }
+
+    // counts the file as skipped because of the error and reports it
+    fn read_failed(&self, file_path: &str, err: std::io::Error) {
+        self.count_failure(&err);
+        report_read_error(file_path, err, self.quiet);
+    }
+
+    fn count_matched(&self, file_d: &FileData) {
+        let mut stats = self.stats.borrow_mut();
+        stats.files_matched += 1;
+        stats.matches += count_matches(&file_d.occurences);
+    }
+
+    fn count_failure(&self, err: &std::io::Error) {
+        let mut stats = self.stats.borrow_mut();
+        match err.kind() {
+            // invalid utf-8 (or bytes of another encoding) is what a binary file looks like
+            ErrorKind::InvalidData => stats.files_binary += 1,
+            ErrorKind::PermissionDenied => stats.paths_denied += 1,
+            _ => stats.files_failed += 1,
+        }
+    }
+
+    fn total_limit_reached(&self, total_matches: usize) -> bool {
+        match self.max_total {
+            Some(max_total) => return total_matches >= max_total,
+            None => return false,
+        }
+    }
+}
+
+// path of a file found under `root`, without the root itself; the search path itself is its file name
+fn relative_path(file_path: &str, root: &str) -> String {
+    return match Path::new(file_path).strip_prefix(root) {
+        Ok(relative) if !relative.as_os_str().is_empty() => relative.to_string_lossy().into_owned(),
+        _ => file_path.to_string(),
+    };
+}
+
+pub fn do_search<M: Matcher + ?Sized>(user_input: &UserInput, matcher: &M) -> Vec<FileData> {
+    return do_search_with_stats(user_input, matcher).0;
+}
+
+pub fn do_search_with_stats<M: Matcher + ?Sized>(user_input: &UserInput, matcher: &M) -> (Vec<FileData>, SearchStats) {
+    let mut file_search_data = Vec::<FileData>::with_capacity(10);
+
+    let stats = search_each(user_input, matcher, &AtomicBool::new(false), &mut |file_d| {
+        file_search_data.push(file_d);
+        return true;
+    });
+
+    if let Some(sort_key) = user_input.sort_by {
+        sort_file_data(&mut file_search_data, sort_key, user_input.sort_descending);
+    }
+
+    if user_input.has_opt_flag(OptFlag::FuzzyRank) {
+        rank_by_distance(&mut file_search_data);
+    }
+
+    return (file_search_data, stats);
+}
+
+// same search as `do_search`, but every file with matches goes to `on_file_data` as soon as it's searched,
+// so results can be shown while the search goes on; the search stops early when `on_file_data`
+// returns false or when `stop` is set by another thread
+pub fn search_each<M: Matcher + ?Sized, F: FnMut(FileData) -> bool>(user_input: &UserInput, matcher: &M, stop: &AtomicBool, on_file_data: &mut F) -> SearchStats {
+    let opts = SearchOpts::from_user_input(user_input);
+    let index_filter = load_index_filter(user_input, &opts);
+    let mut total_matches: usize = 0;
+
+    walk_search_path(user_input, &opts, &mut |file_path| {
+        if stop.load(Ordering::Relaxed) {
+            return false;
+        }
+
+        if let Some((index, query)) = &index_filter {
+            if !index.may_match(&file_path, query) {
+                return true;
+            }
+        }
+
+        if opts.archives {
+            if let Some(kind) = archive_kind(&file_path) {
+                let mut members_data = Vec::<FileData>::new();
+                search_archive(&file_path, kind, &opts, matcher, &mut members_data, &mut total_matches);
+                for file_d in members_data {
+                    opts.count_matched(&file_d);
+                    if !on_file_data(file_d) {
+                        return false;
+                    }
+                }
+                return !opts.total_limit_reached(total_matches);
+            }
+        }
+
+        let file_limit = opts.file_limit(total_matches);
+        if file_limit != Some(0) {
+            if let Some(occurences) = search_file(&file_path, &opts, matcher, file_limit) {
+                total_matches += count_matches(&occurences);
+                let file_d = FileData {
+                    file_path,
+                    occurences,
+                };
+                opts.count_matched(&file_d);
+                if !on_file_data(file_d) {
+                    return false;
+                }
+            }
+        }
+
+        // global limit stops the whole walk, not only the current file
+        return !opts.total_limit_reached(total_matches);
+    });
+
+    return opts.stats.into_inner();
+}
+
+// searches a single file again, for watch mode; walk filters are checked by the caller,
+// only the ones which apply to the path itself are checked here. Global limits are not applied
+pub fn search_changed_file<M: Matcher + ?Sized>(user_input: &UserInput, matcher: &M, file_path: &str) -> Vec<FileData> {
+    let opts = SearchOpts::from_user_input(user_input);
+    let mut file_search_data = Vec::<FileData>::new();
+
+    if is_index_file(file_path) || opts.exclude_paths.is_some_and(|excl_paths| !filter_path(file_path, excl_paths)) {
+        return file_search_data;
+    }
+
+    if opts.archives {
+        if let Some(kind) = archive_kind(file_path) {
+            let opts = SearchOpts { max_total: None, ..opts };
+            search_archive(file_path, kind, &opts, matcher, &mut file_search_data, &mut 0);
+            return file_search_data;
+        }
+    }
+
+    if let Some(occurences) = search_file(file_path, &opts, matcher, opts.max_count) {
+        file_search_data.push(FileData {
+            file_path: file_path.to_string(),
+            occurences,
+        });
+    }
+
+    return file_search_data;
+}
+
+// searches the search path like tail -f: existing lines first (only the last `tail_lines` if set),
+// then lines appended later, every batch of new matches goes to `on_file_data`;
+// returns only when match limits are reached, otherwise runs until the process is stopped
+pub fn do_follow<M: Matcher + ?Sized, F: FnMut(FileData)>(user_input: &UserInput, matcher: &M, on_file_data: &mut F) -> std::io::Result<()> {
+    let opts = SearchOpts::from_user_input(user_input);
+    let file_path = &user_input.search_path;
+
+    let mut followed = FollowedFile::open(file_path)?;
+    // lines before the tail are read only to number the rest
+    let mut skip_lines = match user_input.tail_lines {
+        Some(tail_lines) => count_lines(file_path)?.saturating_sub(tail_lines),
+        None => 0,
+    };
+    let mut total_matches: usize = 0;
+    let mut line = Vec::<u8>::new();
+
+    loop {
+        // per-file and global limits count matches of the same file here
+        let limit = [opts.max_count, opts.max_total].into_iter().flatten().min()
+            .map(|max_matches| max_matches.saturating_sub(total_matches));
+        if limit == Some(0) {
+            return Ok(());
+        }
+
+        // all lines written since the last check are one batch
+        let mut occurences = Vec::<Occurence>::new();
+        while followed.read_line(&mut line)? {
+            let line_num = followed.line_num;
+            followed.line_num += 1;
+            if line_num < skip_lines {
+                continue;
+            }
+
+            // logs may have a broken char here and there, it's no reason to stop following
+            let line_str = String::from_utf8_lossy(&line);
+            let line_content = line_str.trim_end_matches('\n').trim_end_matches('\r');
+            if !search_line(line_content, line_num, &opts, matcher, limit, &mut occurences) {
+                break;
+            }
+        }
+
+        if !occurences.is_empty() {
+            total_matches += count_matches(&occurences);
+            on_file_data(FileData {
+                file_path: file_path.clone(),
+                occurences,
+            });
+            continue;
+        }
+
+        if followed.rotated(file_path)? {
+            // a new file is followed from its start
+            skip_lines = 0;
+            continue;
+        }
+        std::thread::sleep(FOLLOW_POLL_INTERVAL);
+    }
+}
+
+// file being followed, together with the identity of the opened one to notice rotation
+struct FollowedFile {
+    reader:         BufReader<File>,
+    // device and inode, a path pointing to another file means the file was replaced
+    id:             (u64, u64),
+    // bytes read so far, a file shorter than that was truncated
+    position:       u64,
+    // start of a line which is still being written
+    partial_line:   Vec<u8>,
+    line_num:       usize,
+}
+
+impl FollowedFile {
+    fn open(file_path: &str) -> std::io::Result<Self> {
+        let file = File::open(file_path)?;
+        let metadata = file.metadata()?;
+        if metadata.is_dir() {
+            return Err(std::io::Error::new(ErrorKind::IsADirectory, "only a file can be followed"));
+        }
+
+        return Ok(Self {
+            reader:         BufReader::new(file),
+            id:             (metadata.dev(), metadata.ino()),
+            position:       0,
+            partial_line:   Vec::new(),
+            line_num:       0,
+        });
+    }
+
+    // reads the next complete line into `line`, returns false if there is none yet
+    fn read_line(&mut self, line: &mut Vec<u8>) -> std::io::Result<bool> {
+        let read = self.reader.read_until(b'\n', &mut self.partial_line)?;
+        self.position += read as u64;
+
+        if !self.partial_line.ends_with(b"\n") {
+            return Ok(false);
+        }
+
+        line.clear();
+        std::mem::swap(line, &mut self.partial_line);
+        return Ok(true);
+    }
+
+    // called once everything was read from the opened file, so nothing of the old file is lost;
+    // a missing path is a rotation in progress, the new file is waited for
+    fn rotated(&mut self, file_path: &str) -> std::io::Result<bool> {
+        let metadata = match std::fs::metadata(file_path) {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(err),
+        };
+
+        if (metadata.dev(), metadata.ino()) != self.id {
+            *self = Self::open(file_path)?;
+            return Ok(true);
+        }
+
+        if metadata.len() < self.position {
+            self.reader.seek(SeekFrom::Start(0))?;
+            self.position = 0;
+            self.partial_line.clear();
+            self.line_num = 0;
+            return Ok(true);
+        }
+
+        return Ok(false);
+    }
+}
+
+// a last line without a terminator is counted too
+fn count_lines(file_path: &str) -> std::io::Result<usize> {
+    let mut reader = BufReader::new(File::open(file_path)?);
+    let mut lines: usize = 0;
+    let mut last_byte: Option<u8> = None;
+
+    loop {
+        let buff = reader.fill_buf()?;
+        if buff.is_empty() {
+            break;
+        }
+        lines += memchr_iter(b'\n', buff).count();
+        last_byte = buff.last().copied();
+
+        let consumed = buff.len();
+        reader.consume(consumed);
+    }
+
+    if last_byte.is_some_and(|byte| byte != b'\n') {
+        lines += 1;
+    }
+
+    return Ok(lines);
+}
+
+// the walker already visits files in the byte order of their paths;
+// other keys are compared over the whole result, sorting is stable so ties keep the path order
+fn sort_file_data(file_search_data: &mut Vec<FileData>, sort_key: SortKey, descending: bool) {
+    let order = |ordering: CmpOrdering| if descending { ordering.reverse() } else { ordering };
+
+    match sort_key {
+        SortKey::Path => (),
+        SortKey::Matches => {
+            file_search_data.sort_by(|left, right| order(count_matches(&left.occurences).cmp(&count_matches(&right.occurences))));
+        },
+        SortKey::Modified | SortKey::Created | SortKey::Size => {
+            // metadata is read once per file, files without it (like archive members) go last
+            let mut keyed: Vec<(Option<u128>, FileData)> = file_search_data.drain(..)
+                .map(|file_d| (metadata_sort_key(&file_d.file_path, sort_key), file_d))
+                .collect();
+            keyed.sort_by(|(left, _), (right, _)| match (left, right) {
+                (Some(left), Some(right)) => order(left.cmp(right)),
+                (Some(_), None) => CmpOrdering::Less,
+                (None, Some(_)) => CmpOrdering::Greater,
+                (None, None) => CmpOrdering::Equal,
+            });
+            file_search_data.extend(keyed.into_iter().map(|(_, file_d)| file_d));
+        },
+    }
+}
+
+// times are in nanoseconds since the epoch
+fn metadata_sort_key(file_path: &str, sort_key: SortKey) -> Option<u128> {
+    let metadata = std::fs::metadata(file_path).ok()?;
+    let time = match sort_key {
+        SortKey::Modified => metadata.modified().ok()?,
+        SortKey::Created => metadata.created().ok()?,
+        _ => return Some(metadata.len() as u128),
+    };
+    return Some(time.duration_since(std::time::UNIX_EPOCH).ok()?.as_nanos());
+}
+
+// occurences in every file from the closest to the pattern, files by their closest occurence;
+// sorting is stable, so equally close ones keep their order
+fn rank_by_distance(file_search_data: &mut [FileData]) {
+    for file_d in file_search_data.iter_mut() {
+        file_d.occurences.sort_by_key(|occurence| occurence.best_distance());
+    }
+    file_search_data.sort_by_key(|file_d| file_d.occurences.first().and_then(|occurence| occurence.best_distance()));
+}
+
+// index only helps when files are searched as their raw bytes and patterns are literal,
+// in other cases it's silently not used
+fn load_index_filter(user_input: &UserInput, opts: &SearchOpts) -> Option<(TrigramIndex, TrigramQuery)> {
+    if !user_input.has_opt_flag(OptFlag::UseIndex) {
+        return None;
+    }
+
+    let raw_bytes_searched = opts.encoding.is_none() && !opts.lossy && !opts.decompress && !opts.archives && opts.preprocessor.is_none();
+    let literal_patterns = !user_input.has_opt_flag(OptFlag::Regex) && user_input.fuzzy.is_none() && user_input.query.is_none();
+    if !raw_bytes_searched || !literal_patterns {
+        return None;
+    }
+
+    match TrigramIndex::load(&user_input.search_path) {
+        Ok(index) => {
+            let query = TrigramQuery::new(&user_input.search_patterns, user_input.has_opt_flag(OptFlag::IgnoreCase));
+            return Some((index, query));
+        },
+        Err(err) => {
+            if !opts.quiet {
+                eprintln!("Index of {} can't be used, searching without it", user_input.search_path);
+                eprintln!("Error: {}", err);
+            }
+            return None;
+        }
+    }
+}
+
+// builds the trigram index of the search path or updates it, only changed files are read again;
+// returns numbers of indexed files and of files which were read
+pub fn build_index(user_input: &UserInput) -> std::io::Result<(usize, usize)> {
+    let opts = SearchOpts::from_user_input(user_input);
+    let root = &user_input.search_path;
+    if !Path::new(root).is_dir() {
+        return Err(std::io::Error::new(ErrorKind::InvalidInput, "index can only be built for a directory"));
+    }
+
+    // broken index or index of another version is built from scratch
+    let previous = TrigramIndex::load(root).unwrap_or_else(|_| TrigramIndex::new(root));
+    let mut index = TrigramIndex::new(root);
+    let mut trigram_set = TrigramSet::new();
+    let mut read_files: usize = 0;
+
+    // same files as a search of the directory would go through
+    let mut walker = DirWalker::from_user_input(user_input);
+    walker.walk(root, &mut |file_path| {
+        if is_index_file(&file_path) {
+            return true;
+        }
+
+        match index.add_file(&file_path, &previous, &mut trigram_set) {
+            Ok(true) => read_files += 1,
+            Ok(false) => (),
+            Err(err) => opts.read_failed(&file_path, err),
+        }
+        return true;
+    });
+
+    index.save()?;
+    return Ok((index.files_count(), read_files));
+}
+
+fn is_index_file(file_path: &str) -> bool {
+    return Path::new(file_path).file_name().is_some_and(|name| name == INDEX_FILE_NAME);
+}
+
+// every member is searched as a separate file with a virtual path archive!/member
+fn search_archive<M: Matcher + ?Sized>(archive_path: &str, kind: ArchiveKind, opts: &SearchOpts, matcher: &M, file_search_data: &mut Vec<FileData>, total_matches: &mut usize) {
+    let mut on_member = |member_name: &str, member_reader: &mut dyn Read| {
+        let file_path = member_path(archive_path, member_name);
+        if let Some(excl_paths) = opts.exclude_paths {
+            if !filter_path(&file_path, excl_paths) {
+                opts.stats.borrow_mut().paths_excluded += 1;
+                return true;
+            }
+        }
+
+        let file_limit = opts.file_limit(*total_matches);
+        if file_limit == Some(0) {
+            return false;
+        }
+        opts.stats.borrow_mut().files_searched += 1;
+
+        // members are read whole, as archive readers can't be kept past the current member
+        let mut contents = Vec::<u8>::new();
+        if let Err(err) = member_reader.read_to_end(&mut contents) {
+            opts.read_failed(&file_path, err);
+            return true;
+        }
+
+        let mut reader = match text_reader(Box::new(Cursor::new(contents)), opts) {
+            Ok(reader) => reader,
+            Err(err) => {
+                opts.read_failed(&file_path, err);
+                return true;
+            }
+        };
+
+        if let Some(occurences) = search_reader(&file_path, &mut reader, opts, matcher, file_limit) {
+            *total_matches += count_matches(&occurences);
+            file_search_data.push(FileData {
+                file_path,
+                occurences,
+            });
+        }
+
+        return !opts.total_limit_reached(*total_matches);
+    };
+    let mut on_error = |file_path: &str, err: std::io::Error| opts.read_failed(file_path, err);
+
+    if let Err(err) = for_each_member(archive_path, kind, &mut on_member, &mut on_error) {
+        opts.read_failed(archive_path, err);
+    }
+}
+
+pub fn do_replace<M: Matcher + ?Sized>(user_input: &UserInput, matcher: &M, replacement: &str) -> Vec<FileReplaceData> {
+    let opts = SearchOpts::from_user_input(user_input);
+    let mut file_replace_data = Vec::<FileReplaceData>::with_capacity(10);
+    // with -L a file may be reached through a few paths (links), it's replaced only once
+    let mut replaced_files = HashSet::<PathBuf>::new();
+
+    walk_search_path(user_input, &opts, &mut |file_path| {
+        if let Ok(canonical_path) = std::fs::canonicalize(&file_path) {
+            if !replaced_files.insert(canonical_path) {
+                return true;
+            }
+        }
+
+        if let Some(contents) = read_file_contents(&file_path, opts.quiet) {
+            if opts.file_query && !matcher.accepts_text(contents.as_bytes()) {
+                return true;
+            }
+            if let Some(replace_data) = replace_in_contents(file_path, &contents, matcher, replacement) {
+                file_replace_data.push(replace_data);
+            }
+        }
+        return true;
+    });
+
+    return file_replace_data;
+}
+
+// matching is done line by line, same as for search, so line terminators are never replaced
+fn replace_in_contents<M: Matcher + ?Sized>(file_path: String, contents: &str, matcher: &M, replacement: &str) -> Option<FileReplaceData> {
+    let mut old_lines = Vec::<String>::with_capacity(contents.len() / 40);
+    let mut new_lines = Vec::<String>::with_capacity(contents.len() / 40);
+    let mut replacements: usize = 0;
+
+    for line in contents.split_inclusive('\n') {
+        let line_content = line.trim_end_matches('\n').trim_end_matches('\r');
+        let line_terminator = &line[line_content.len()..];
+
+        let (replaced, count) = replace_all(matcher, line_content.as_bytes(), replacement.as_bytes());
+        old_lines.push(line.to_string());
+        if count == 0 {
+            new_lines.push(line.to_string());
+            continue;
+        }
+
+        // matches are on char boundaries, so nothing should actually be lost here
+        let mut new_line = String::from_utf8_lossy(&replaced).into_owned();
+        new_line.push_str(line_terminator);
+        new_lines.push(new_line);
+        replacements += count;
+    }
+
+    if replacements == 0 {
+        return None;
+    }
+
+    return Some(FileReplaceData {
+        file_path,
+        old_lines,
+        new_lines,
+        replacements,
+    });
+}
+
+// writes `contents` into a temporary file next to `file_path` and renames it over the original,
+// so readers see either the old or the new file; permissions of the original are kept
+pub fn write_file_atomically(file_path: &str, contents: &str, quiet: bool) -> bool {
+    // renaming over a symlink would replace the link itself, so the file it points to is replaced instead
+    let path = match std::fs::canonicalize(file_path) {
+        Ok(path) => path,
+        Err(err) => {
+            if !quiet {
+                eprintln!("Can't resolve the path of the file: {}\nError: {}", file_path, err);
+            }
+            return false;
+        }
+    };
+    let path = path.as_path();
+    let permissions = match std::fs::metadata(path) {
+        Ok(metadata) => metadata.permissions(),
+        Err(err) => {
+            if !quiet {
+                eprintln!("Can't read metadata of the file: {}\nError: {}", file_path, err);
+            }
+            return false;
+        }
+    };
+
+    let file_name = match path.file_name() {
+        Some(name) => name.to_string_lossy(),
+        None => {
+            if !quiet {
+                eprintln!("Path doesn't point to a file: {}", file_path);
+            }
+            return false;
+        }
+    };
+    let tmp_path = path.with_file_name(format!(".{}.minigrep-tmp", file_name));
+
+    let write_result = File::create(&tmp_path).and_then(|mut tmp_file| {
+        tmp_file.write_all(contents.as_bytes())?;
+        tmp_file.set_permissions(permissions)?;
+        tmp_file.sync_all()?;
+        return std::fs::rename(&tmp_path, path);
+    });
+
+    if let Err(err) = write_result {
+        let _ = std::fs::remove_file(&tmp_path);
+        if !quiet {
+            eprintln!("Failed to write replaced contents to the file: {}\nError: {}", file_path, err);
+        }
+        return false;
+    }
+
+    return true;
+}
+
+// calls `on_file` for the search path itself or for every file in it for directory search,
+// skipping paths excluded by the user; walk stops as soon as `on_file` returns false
+fn walk_search_path<F: FnMut(String) -> bool>(user_input: &UserInput, opts: &SearchOpts, on_file: &mut F) {
+    let do_dir_search = user_input.has_opt_flag(OptFlag::Recursive) || user_input.has_opt_flag(OptFlag::Dir);
+
+    if let Some(excl_paths) = opts.exclude_paths {
+        if !filter_path(&user_input.search_path, excl_paths) {
+            opts.stats.borrow_mut().paths_excluded += 1;
+            return;
+        }
+    }
+
+    if do_dir_search {
+        let mut walker = DirWalker::from_user_input(user_input);
+        walker.walk(&user_input.search_path, &mut |file_path| {
+            if is_index_file(&file_path) {
+                return true;
+            }
+            return on_file(file_path);
+        });
+
+        let mut stats = opts.stats.borrow_mut();
+        stats.paths_excluded += walker.excluded_entries();
+        stats.files_filtered += walker.filtered_files();
+        stats.paths_denied += walker.denied_dirs();
+    } else {
+        on_file(user_input.search_path.clone());
+    }
+}
+
+// filters of files by their metadata, a file which metadata can't be read passes no filter
+pub struct FileFilters {
+    max_filesize:   Option<u64>,
+    // the last modification must be in this window
+    newer_than:     Option<SystemTime>,
+    older_than:     Option<SystemTime>,
+    owner_uid:      Option<u32>,
+    // any of the execute bits is set
+    executable:     bool,
+}
+
+impl FileFilters {
+    pub fn from_user_input(user_input: &UserInput) -> Self {
+        Self {
+            max_filesize:   user_input.max_filesize,
+            newer_than:     user_input.newer_than,
+            older_than:     user_input.older_than,
+            owner_uid:      user_input.owner_uid,
+            executable:     user_input.has_opt_flag(OptFlag::Executable),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.max_filesize.is_none() && self.newer_than.is_none() && self.older_than.is_none()
+            && self.owner_uid.is_none() && !self.executable;
+    }
+
+    // the path is followed if it's a symlink
+    pub fn allows_path(&self, file_path: &str) -> bool {
+        if self.is_empty() {
+            return true;
+        }
+        return std::fs::metadata(file_path).is_ok_and(|metadata| self.allows(&metadata));
+    }
+
+    pub fn allows(&self, metadata: &Metadata) -> bool {
+        if self.max_filesize.is_some_and(|max_filesize| metadata.len() > max_filesize) {
+            return false;
+        }
+
+        if self.newer_than.is_some() || self.older_than.is_some() {
+            let modified = match metadata.modified() {
+                Ok(modified) => modified,
+                Err(_) => return false,
+            };
+            if self.newer_than.is_some_and(|newer_than| modified < newer_than) {
+                return false;
+            }
+            if self.older_than.is_some_and(|older_than| modified >= older_than) {
+                return false;
+            }
+        }
+
+        if self.owner_uid.is_some_and(|owner_uid| metadata.uid() != owner_uid) {
+            return false;
+        }
+
+        if self.executable && metadata.mode() & 0o111 == 0 {
+            return false;
+        }
+
+        return true;
+    }
+}
+
+// why an entry of a directory is skipped by the walk
+pub enum EntrySkip {
+    Hidden,
+    NotUnicode,
+    Excluded,
+    Symlink,
+}
+
+// device and inode, identify a directory regardless of the path it was reached by
+type DirId = (u64, u64);
+
+// walks a directory tree and reports every file which passes the filters,
+// all decisions of what to visit are made here, so every mode sees the same files
+pub struct DirWalker<'a> {
+    quiet:              bool,
+    exclude_paths:      Option<&'a Vec<String>>,
+    follow_symlinks:    bool,
+    one_file_system:    bool,
+    // files and directories which names start with '.'
+    include_hidden:     bool,
+    // depth of an entry is its distance from the root, entries of the root have depth 1
+    min_depth:          usize,
+    max_depth:          Option<usize>,
+    // directories from the root to the current one, to detect loops made by symlinks
+    ancestors:          Vec<DirId>,
+    // device of the root, for --one-file-system
+    root_dev:           Option<u64>,
+    // with sorted results files are visited in the byte order of their paths, Some(true) in descending order,
+    // so the walk goes through paths in the same order on every machine
+    sort_descending:    Option<bool>,
+    file_filters:       FileFilters,
+    // entries skipped by -e or by file filters and directories which couldn't be read, for --stats
+    excluded_entries:   usize,
+    filtered_files:     usize,
+    denied_dirs:        usize,
+}
+
+impl<'a> DirWalker<'a> {
+    pub fn from_user_input(user_input: &'a UserInput) -> Self {
+        // directory search without -r is a walk which doesn't go deeper than the root entries
+        let max_depth = if user_input.has_opt_flag(OptFlag::Recursive) {
+            user_input.max_depth
+        } else {
+            Some(user_input.max_depth.map_or(1, |max_depth| max_depth.min(1)))
+        };
+
+        Self {
+            quiet:              user_input.has_opt_flag(OptFlag::Quiet),
+            exclude_paths:      user_input.exclude_paths.as_ref(),
+            follow_symlinks:    user_input.has_opt_flag(OptFlag::FollowSymlinks),
+            one_file_system:    user_input.has_opt_flag(OptFlag::OneFileSystem),
+            include_hidden:     user_input.has_opt_flag(OptFlag::Hidden),
+            min_depth:          user_input.min_depth.unwrap_or(0),
+            max_depth,
+            ancestors:          Vec::<DirId>::new(),
+            root_dev:           None,
+            sort_descending:    user_input.sort_by.map(|_| user_input.sort_descending),
+            file_filters:       FileFilters::from_user_input(user_input),
+            excluded_entries:   0,
+            filtered_files:     0,
+            denied_dirs:        0,
+        }
+    }
+
+    pub fn excluded_entries(&self) -> usize {
+        return self.excluded_entries;
+    }
+
+    pub fn filtered_files(&self) -> usize {
+        return self.filtered_files;
+    }
+
+    pub fn denied_dirs(&self) -> usize {
+        return self.denied_dirs;
+    }
+
+    // returns false if the walk was stopped by `on_file`
+    pub fn walk<F: FnMut(String) -> bool>(&mut self, root_path: &str, on_file: &mut F) -> bool {
+        self.set_root(root_path);
+        return self.walk_dir(root_path, 1, on_file);
+    }
+
+    // the checks below are public, so directories followed in other ways (watch mode) see the same entries as the walk;
+    // the root is needed for --one-file-system
+    pub fn set_root(&mut self, root_path: &str) {
+        self.ancestors.clear();
+        self.root_dev = None;
+
+        if let Ok(metadata) = std::fs::metadata(root_path) {
+            self.ancestors.push((metadata.dev(), metadata.ino()));
+            self.root_dev = Some(metadata.dev());
+        }
+    }
+
+    // hidden, excluded and (unless followed) symlinked entries are skipped, an accepted path is returned as a string
+    pub fn accepts_entry(&self, entry_path: &Path, is_symlink: bool) -> Result<String, EntrySkip> {
+        let file_name = entry_path.file_name().ok_or(EntrySkip::NotUnicode)?;
+        if !self.include_hidden && file_name.as_encoded_bytes().starts_with(b".") {
+            return Err(EntrySkip::Hidden);
+        }
+
+        let entry_path = entry_path.to_str().ok_or(EntrySkip::NotUnicode)?.to_string();
+        if let Some(excl_paths) = self.exclude_paths {
+            if !filter_path(&entry_path, excl_paths) {
+                return Err(EntrySkip::Excluded);
+            }
+        }
+
+        if is_symlink && !self.follow_symlinks {
+            return Err(EntrySkip::Symlink);
+        }
+
+        return Ok(entry_path);
+    }
+
+    // size, time, owner and permission filters of files
+    pub fn accepts_file(&self, file_path: &str) -> bool {
+        return self.file_filters.allows_path(file_path);
+    }
+
+    // files at `depth` are searched
+    pub fn depth_allowed(&self, depth: usize) -> bool {
+        return depth >= self.min_depth && self.max_depth.is_none_or(|max_depth| depth <= max_depth);
+    }
+
+    // a directory at `depth` is entered, its entries are at `depth + 1`
+    pub fn descends_below(&self, depth: usize) -> bool {
+        return self.max_depth.is_none_or(|max_depth| depth < max_depth);
+    }
+
+    // with --one-file-system only directories on the device of the root are entered
+    pub fn on_root_filesystem(&self, dev: u64) -> bool {
+        return !self.one_file_system || self.root_dev.is_none_or(|root_dev| root_dev == dev);
+    }
+
+    // `depth` is the depth of entries of `dir_path`
+    fn walk_dir<F: FnMut(String) -> bool>(&mut self, dir_path: &str, depth: usize, on_file: &mut F) -> bool {
+        let quiet = self.quiet;
+
+        let dir_iter = match std::fs::read_dir(dir_path) {
+            Ok(it) => it,
+            Err(err) => match err.kind() {
+                ErrorKind::PermissionDenied => {
+                    self.denied_dirs += 1;
+                    if !quiet {
+                        eprintln!("Permission denied to access dir by path: {}", dir_path);
+                    }
+                    return true;
+                },
+                ErrorKind::NotADirectory => {
+                    if !quiet {
+                        eprintln!("Found a file, not a directory with provided path: {}", dir_path);
+                        eprintln!("Consider unspecify flags -d,-r if presented");
+                    }
+                    return true;
+                },
+                ErrorKind::NotFound => {
+                    if !quiet {
+                        eprintln!("Directory wasn't found by path: {}", dir_path);
+                    }
+                    return true;
+                },
+                _ => {
+                    if !quiet {
+                        eprintln!("Unexpected error occured when opening directory")
+                    }
+                    return true;
+                }
+            }
+        };
+
+        let dir_entries: Box<dyn Iterator<Item = std::io::Result<DirEntry>>> = match self.sort_descending {
+            Some(descending) => {
+                // entries which couldn't be read have no key, they are only reported
+                let mut entries: Vec<(Option<Vec<u8>>, std::io::Result<DirEntry>)> = dir_iter
+                    .map(|entry| (entry.as_ref().ok().map(|entry| self.path_sort_key(entry)), entry))
+                    .collect();
+                entries.sort_by(|(left, _), (right, _)| {
+                    let ordering = left.cmp(right);
+                    if descending { ordering.reverse() } else { ordering }
+                });
+                Box::new(entries.into_iter().map(|(_, entry)| entry))
+            },
+            None => Box::new(dir_iter),
+        };
+
+        for dir_entry_opt in dir_entries {
+            match dir_entry_opt {
+                Ok(dir_entry) => {
+                    if let Ok(file_type) = dir_entry.file_type() {
+                        let entry_full_path = match self.accepts_entry(&dir_entry.path(), file_type.is_symlink()) {
+                            Ok(entry_full_path) => entry_full_path,
+                            Err(EntrySkip::NotUnicode) => {
+                                if !quiet {
+                                    eprintln!("Path is not a valid unicode, skipping");
+                                }
+                                continue;
+                            },
+                            Err(EntrySkip::Excluded) => {
+                                self.excluded_entries += 1;
+                                continue;
+                            },
+                            Err(_) => continue,
+                        };
+
+                        // dir_entry.file_type() doesn't follow symlinks, the link target type is needed
+                        let mut file_type = file_type;
+                        if file_type.is_symlink() {
+                            match std::fs::metadata(&entry_full_path) {
+                                Ok(target_metadata) => file_type = target_metadata.file_type(),
+                                Err(_) => {
+                                    if !quiet {
+                                        eprintln!("Symbolic link is broken: {}\nskipping", entry_full_path);
+                                    }
+                                    continue;
+                                }
+                            }
+                        }
+
+                        // if entry is file
+                        let keep_walking = if FileType::is_file(&file_type) {
+                            if !self.depth_allowed(depth) {
+                                true
+                            } else if !self.accepts_file(&entry_full_path) {
+                                self.filtered_files += 1;
+                                true
+                            } else {
+                                on_file(entry_full_path)
+                            }
+                        }
+                        // if entry is dir
+                        else if FileType::is_dir(&file_type) && self.descends_below(depth) {
+                            self.enter_dir(&entry_full_path, depth + 1, on_file)
+                        }
+                        else {
+                            true
+                        };
+
+                        if !keep_walking {
+                            return false;
+                        }
+                    } else {
+                        if !quiet {
+                            eprintln!("Can't get a file type for the path: {:?}\nskipping", dir_entry.path());
+                        }
+                    }
+                },
+                Err(err) => {
+                    if !quiet {
+                        eprintln!("Dir entry data error: {}\nskipping", err);
+                    }
+                },
+            }
+        }
+
+        return true;
+    }
+
+    // a directory sorts as its name followed by '/', which is how its files' paths continue, so sorting
+    // entries of every directory gives the byte order of whole paths: a.txt comes before a/x as '.' < '/'
+    fn path_sort_key(&self, entry: &DirEntry) -> Vec<u8> {
+        let mut key = entry.file_name().as_encoded_bytes().to_vec();
+        let is_dir = match entry.file_type() {
+            Ok(file_type) if file_type.is_symlink() => self.follow_symlinks && std::fs::metadata(entry.path()).is_ok_and(|metadata| metadata.is_dir()),
+            Ok(file_type) => file_type.is_dir(),
+            Err(_) => false,
+        };
+        if is_dir {
+            key.push(b'/');
+        }
+        return key;
+    }
+
+    fn enter_dir<F: FnMut(String) -> bool>(&mut self, dir_path: &str, depth: usize, on_file: &mut F) -> bool {
+        // without following symlinks a loop can't appear, so the checks are skipped
+        if !self.follow_symlinks && !self.one_file_system {
+            return self.walk_dir(dir_path, depth, on_file);
+        }
 
-pub struct FileData {
-    pub file_path:          String,
-    pub file_tokens:        Vec<Token>,
-}
+        let dir_id = match self.check_dir(dir_path) {
+            Some(dir_id) => dir_id,
+            None => return true,
+        };
 
-pub fn do_search(user_input: &UserInput) -> Vec<FileData> {
-    let do_recursive_search = user_input.has_opt_flag(OptFlag::Recursive);
-    let do_dir_search = do_recursive_search || user_input.has_opt_flag(OptFlag::Dir);
-    let be_quiet = user_input.has_opt_flag(OptFlag::Quiet);
-    let line_numbers = user_input.has_opt_flag(OptFlag::LineNumbers);
+        self.ancestors.push(dir_id);
+        let keep_walking = self.walk_dir(dir_path, depth, on_file);
+        self.ancestors.pop();
 
-    let mut file_search_data: Vec<FileData>;
+        return keep_walking;
+    }
 
-    if do_dir_search {
-        file_search_data = Vec::with_capacity(10);
-        match &user_input.exclude_paths {
-            Some(excl_paths) => {
-                if filter_path(&user_input.search_path, excl_paths) {
-                    search_dir(&user_input.search_path, &mut file_search_data, do_recursive_search, be_quiet, line_numbers, user_input.exclude_paths.as_ref());
+    // checks if the walk may descend into a directory, returns its id if so
+    fn check_dir(&self, dir_path: &str) -> Option<DirId> {
+        let metadata = match std::fs::metadata(dir_path) {
+            Ok(metadata) => metadata,
+            Err(err) => {
+                if !self.quiet {
+                    eprintln!("Can't read metadata of the directory: {}\nError: {}", dir_path, err);
                 }
-            },
-            None => {
-                search_dir(&user_input.search_path, &mut file_search_data, do_recursive_search, be_quiet, line_numbers, None);
+                return None;
             }
+        };
+        let dir_id = (metadata.dev(), metadata.ino());
+
+        if !self.on_root_filesystem(dir_id.0) {
+            return None;
         }
-    } else {
-        file_search_data = Vec::with_capacity(1);
-        match &user_input.exclude_paths {
-            Some(excl_paths) => {
-                if filter_path(&user_input.search_path, excl_paths) {
-                    if let Some(file_data) = get_file_data(&user_input.search_path, be_quiet, line_numbers) {
-                        file_search_data.push(file_data);
-                    }
-                }
-            },
-            None => {
-                if let Some(file_data) = get_file_data(&user_input.search_path, be_quiet, line_numbers) {
-                    file_search_data.push(file_data);
-                }
+
+        if self.ancestors.contains(&dir_id) {
+            if !self.quiet {
+                eprintln!("Filesystem loop detected: {} leads to one of its parent directories, skipping", dir_path);
             }
+            return None;
         }
-    }
 
-    return file_search_data;
+        return Some(dir_id);
+    }
 }
 
-fn search_dir(search_path: &str, file_data_out: &mut Vec<FileData>, recursive: bool, quiet: bool, line_numbers: bool, exclude_paths: Option<&Vec<String>>) {
-    let dir_iter = match std::fs::read_dir(search_path) {
-        Ok(it) => it,
-        Err(err) => match err.kind() {
-            ErrorKind::PermissionDenied => {
-                if !quiet {
-                    eprintln!("Permission denied to access dir by path: {}", search_path);
-                }
-                return ();
-            },
-            ErrorKind::NotADirectory => {
-                if !quiet {
-                    eprintln!("Found a file, not a directory with provided path: {}", search_path);
-                    eprintln!("Consider unspecify flags -d,-r if presented");
-                }
-                return ();
-            },
-            ErrorKind::NotFound => {
-                if !quiet {
-                    eprintln!("Directory wasn't found by path: {}", search_path);
-                }
-                return ();
-            },
-            _ => {
-                if !quiet {
-                    eprintln!("Unexpected error occured when opening directory")
+// reads the file line by line and stops as soon as `limit` matches are found,
+// so the rest of the file is never read
+fn search_file<M: Matcher + ?Sized>(file_path: &str, opts: &SearchOpts, matcher: &M, limit: Option<usize>) -> Option<Vec<Occurence>> {
+    opts.stats.borrow_mut().files_searched += 1;
+
+    let raw_reader: Box<dyn BufRead + Send> = match opts.preprocessor_for(file_path) {
+        Some(command) => match preprocessor_reader(command, file_path) {
+            Ok(reader) => Box::new(reader),
+            Err(err) => {
+                opts.read_failed(file_path, err);
+                return None;
+            }
+        },
+        // the file is opened once, both for mapping and for reading it as a stream
+        None => {
+            let file = match open_file(file_path, opts.quiet) {
+                Ok(file) => file,
+                Err(err) => {
+                    opts.count_failure(&err);
+                    return None;
                 }
-                return ();
+            };
+            if let Some(mapped_file) = map_file(&file, opts) {
+                return search_mapped(file_path, &mapped_file, opts, matcher, limit);
             }
+            Box::new(BufReader::new(file))
+        },
+    };
+
+    let mut reader = match text_reader(raw_reader, opts) {
+        Ok(reader) => reader,
+        Err(err) => {
+            opts.read_failed(file_path, err);
+            return None;
         }
     };
+    return search_reader(file_path, &mut reader, opts, matcher, limit);
+}
 
-    for dir_entry_opt in dir_iter {
-        match dir_entry_opt {
-            Ok(dir_entry) => {
-                if let Ok(file_type) = dir_entry.file_type() {
-                    let entry_full_path = match dir_entry.path().to_str() {
-                        Some(slice) => {
-                            match String::from_str(slice) {
-                                Ok(s) => s,
-                                Err(_) => continue,
-                            }
-                        },
-                        None => {
-                            if !quiet {
-                                eprintln!("Path is not a valid unicode, skipping");
-                            }
-                            continue;
-                        }
-                    };
-
-                    // if entry is file
-                    if FileType::is_file(&file_type) {
-                        match exclude_paths {
-                            Some(excl_paths) => {
-                                if filter_path(&entry_full_path, excl_paths) {
-                                    if let Some(tokens) = get_file_tokens(&entry_full_path, quiet, line_numbers) {
-                                        let file_data = FileData {
-                                            file_path: entry_full_path,
-                                            file_tokens: tokens,
-                                        };
-                                        file_data_out.push(file_data);
-                                    };
-                                }
-                            },
-                            None => {
-                                if let Some(tokens) = get_file_tokens(&entry_full_path, quiet, line_numbers) {
-                                    let file_data = FileData {
-                                        file_path: entry_full_path,
-                                        file_tokens: tokens,
-                                    };
-                                    file_data_out.push(file_data);
-                                };
-                            }
-                        }
-                    }
-                    // if entry is dir
-                    else if recursive && FileType::is_dir(&file_type) {
-                        match exclude_paths {
-                            Some(excl_paths) => {
-                                if filter_path(&entry_full_path, excl_paths) {
-                                    search_dir(&entry_full_path, file_data_out, recursive, quiet, line_numbers, exclude_paths);
-                                }
-                            },
-                            None => {
-                                search_dir(&entry_full_path, file_data_out, recursive, quiet, line_numbers, None);
-                            }
-                        }
-                    }
-                } else {
-                    if !quiet {
-                        eprintln!("Can't get a file type for the path: {:?}\nskipping", dir_entry.path());
-                    }
-                }
-            },
-            Err(err) => {
-                if !quiet {
-                    eprintln!("Dir entry data error: {}\nskipping", err);
-                }
-            },
+// big regular files in utf-8 are mapped into memory instead of being copied through a buffer,
+// None means the file is read as a stream (and errors are reported there)
+fn map_file(file: &File, opts: &SearchOpts) -> Option<Mmap> {
+    if opts.encoding.is_some() || opts.lossy {
+        return None;
+    }
+
+    let metadata = file.metadata().ok()?;
+    if !metadata.is_file() || metadata.len() < MMAP_MIN_FILE_SIZE {
+        return None;
+    }
+
+    // SAFETY: the file may be changed by other processes while it's mapped, which would change the text
+    // being searched, or truncate it under us; grep tools accept the same risk for speed
+    let mapped_file = unsafe { Mmap::map(file) }.ok()?;
+
+    // such files need decoding, which streams do
+    if detect_bom(&mapped_file).is_some() || (opts.decompress && detect_compression(&mapped_file).is_some()) {
+        return None;
+    }
+
+    return Some(mapped_file);
+}
+
+// lines are sliced right out of the mapped bytes, without copying them
+fn search_mapped<M: Matcher + ?Sized>(file_path: &str, contents: &[u8], opts: &SearchOpts, matcher: &M, limit: Option<usize>) -> Option<Vec<Occurence>> {
+    if opts.multiline || opts.file_query {
+        opts.stats.borrow_mut().bytes_read += contents.len() as u64;
+        let text = match std::str::from_utf8(contents) {
+            Ok(text) => text,
+            Err(_) => {
+                opts.read_failed(file_path, invalid_utf8_error());
+                return None;
+            }
+        };
+        return search_text(text, opts, matcher, limit);
+    }
+
+    // same as with read_line, every line is validated only when it's reached, so the search stops
+    // at the same point as search_reader and pages after it are never touched
+    let mut occurences = Vec::<Occurence>::new();
+    let mut line_start: usize = 0;
+    let mut line_num: usize = 0;
+
+    while line_start < contents.len() {
+        let line_end = memchr(b'\n', &contents[line_start..]).map_or(contents.len(), |pos| line_start + pos + 1);
+        opts.stats.borrow_mut().bytes_read += (line_end - line_start) as u64;
+
+        let line = match std::str::from_utf8(&contents[line_start..line_end]) {
+            Ok(line) => line,
+            Err(_) => {
+                opts.read_failed(file_path, invalid_utf8_error());
+                return None;
+            }
+        };
+
+        let line_content = line.trim_end_matches('\n').trim_end_matches('\r');
+        let keep_reading = search_line(line_content, line_num, opts, matcher, limit, &mut occurences);
+        line_num += 1;
+        line_start = line_end;
+
+        if !keep_reading {
+            break;
         }
     }
+
+    if occurences.is_empty() {
+        return None;
+    }
+
+    return Some(occurences);
 }
 
-fn get_file_data(file_path: &String, quiet: bool, line_numbers: bool) -> Option<FileData> {
-    let mut file = match File::open(file_path) {
-        Ok(f) => f,
-        Err(err) => match err.kind() {
-            ErrorKind::PermissionDenied => {
-                if !quiet {
-                    eprintln!("Permission denied for file access at path: {}", file_path);
-                }
-                return None
-            },
-            ErrorKind::NotFound => {
-                if !quiet {
-                    eprintln!("File wasn't found at path: {}", file_path);
-                }
-                return None
-            },
-            ErrorKind::IsADirectory => {
-                if !quiet {
-                    eprintln!("Directory found at path, not a file: {}", file_path);
-                    eprintln!("Consider specify flags for directory search: -d,-r");
-                }
-                return None
-            },
-            _ => {
-                if !quiet {
-                    eprintln!("Unexpected error when opening a file: {}", file_path);
-                }
-                return None
+// the error read_line gives for invalid utf-8
+fn invalid_utf8_error() -> std::io::Error {
+    return std::io::Error::new(ErrorKind::InvalidData, "stream did not contain valid UTF-8");
+}
+
+// text of a whole file, multiline patterns and queries over files need all of it at once
+fn search_text<M: Matcher + ?Sized>(text: &str, opts: &SearchOpts, matcher: &M, limit: Option<usize>) -> Option<Vec<Occurence>> {
+    if opts.file_query && !matcher.accepts_text(text.as_bytes()) {
+        return None;
+    }
+
+    if opts.multiline {
+        return search_multiline(text, opts, matcher, limit);
+    }
+
+    let mut occurences = Vec::<Occurence>::new();
+    let text = text.strip_suffix('\n').unwrap_or(text);
+
+    for (line_num, line) in text.split('\n').enumerate() {
+        let line_content = line.trim_end_matches('\r');
+        if !search_line(line_content, line_num, opts, matcher, limit, &mut occurences) {
+            break;
+        }
+    }
+
+    if occurences.is_empty() {
+        return None;
+    }
+
+    return Some(occurences);
+}
+
+fn search_reader<M: Matcher + ?Sized>(file_path: &str, reader: &mut Box<dyn BufRead>, opts: &SearchOpts, matcher: &M, limit: Option<usize>) -> Option<Vec<Occurence>> {
+    if opts.multiline || opts.file_query {
+        let mut text = String::new();
+        if let Err(err) = reader.read_to_string(&mut text) {
+            opts.read_failed(file_path, err);
+            return None;
+        }
+        opts.stats.borrow_mut().bytes_read += text.len() as u64;
+        return search_text(&text, opts, matcher, limit);
+    }
+
+    let mut occurences = Vec::<Occurence>::new();
+    let mut line = String::new();
+    let mut line_num: usize = 0;
+
+    loop {
+        line.clear();
+        match reader.read_line(&mut line) {
+            Ok(0) => break,
+            Ok(read) => opts.stats.borrow_mut().bytes_read += read as u64,
+            Err(err) => {
+                opts.read_failed(file_path, err);
+                return None;
             }
         }
+
+        let line_content = line.trim_end_matches('\n').trim_end_matches('\r');
+        let keep_reading = search_line(line_content, line_num, opts, matcher, limit, &mut occurences);
+        line_num += 1;
+
+        if !keep_reading {
+            break;
+        }
+    }
+
+    if occurences.is_empty() {
+        return None;
+    }
+
+    return Some(occurences);
+}
+
+// matches are searched in the whole text, every match is reported together with all lines it spans,
+// matches sharing a line end up in the same occurence
+fn search_multiline<M: Matcher + ?Sized>(text: &str, opts: &SearchOpts, matcher: &M, limit: Option<usize>) -> Option<Vec<Occurence>> {
+    let bytes = text.as_bytes();
+    let mut occurences = Vec::<Occurence>::new();
+    // lines of the occurence which is still being extended, with its first line number and matches
+    let mut pending: Option<(Range<usize>, usize, Vec<Match>)> = None;
+    let mut line_num: usize = 0;
+    let mut counted_pos: usize = 0;
+
+    let push_pending = |pending: (Range<usize>, usize, Vec<Match>), occurences: &mut Vec<Occurence>| {
+        let (lines, first_line_num, matches) = pending;
+        let distances = matches.iter().filter_map(|found| matcher.match_distance(bytes, *found)).collect();
+        let matches = matches.iter()
+            .map(|found| Match {
+                start: found.start - lines.start,
+                // a trailing line break of a match is not a part of printed lines
+                end: found.end.min(lines.end) - lines.start,
+            })
+            .collect();
+        occurences.push(Occurence {
+            token: make_token(text[lines].to_string(), first_line_num, opts.line_numbers),
+            matches,
+            distances,
+        });
     };
 
-    let mut contents_buff = String::new();
+    for found in find_iter(matcher, bytes) {
+        let last_byte = if found.end > found.start && bytes[found.end - 1] == b'\n' { found.end - 1 } else { found.end };
+        let lines_start = match memrchr(b'\n', &bytes[..found.start]) {
+            Some(ind) => ind + 1,
+            None => 0,
+        };
+        let lines_end = match memchr(b'\n', &bytes[last_byte..]) {
+            Some(ind) => last_byte + ind,
+            None => bytes.len(),
+        };
 
-    match file.read_to_string(&mut contents_buff) {
-        Ok(_) => (),
-        Err(err) => match err.kind() {
-            ErrorKind::PermissionDenied => {
-                if !quiet {
-                    eprintln!("Permission denied for file access at path: {}", file_path);
-                }
-                return None
-            },
-            ErrorKind::NotFound => {
-                if !quiet {
-                    eprintln!("File wasn't found at path: {}", file_path);
-                }
-                return None
-            },
-            ErrorKind::IsADirectory => {
-                if !quiet {
-                    eprintln!("Directory found at path, not a file: {}", file_path);
-                }
-                return None
-            },
-            _ => {
-                if !quiet {
-                    eprintln!("Unexpected error for file access at path: {}", file_path);
-                }
-                return None
+        line_num += memchr_iter(b'\n', &bytes[counted_pos..lines_start]).count();
+        counted_pos = lines_start;
+
+        if let Some((lines, _, matches)) = pending.as_mut() {
+            if lines_start <= lines.end {
+                lines.end = lines.end.max(lines_end);
+                matches.push(found);
+                continue;
+            }
+        }
+
+        if let Some(finished) = pending.take() {
+            push_pending(finished, &mut occurences);
+            if limit.is_some_and(|limit| count_matches(&occurences) >= limit) {
+                break;
             }
         }
+        pending = Some((lines_start..lines_end, line_num, vec![found]));
     }
 
-    let file_tokens = parse_to_tokens(&contents_buff, line_numbers);
-    let file_path = String::from_str(file_path).unwrap();
+    if let Some(finished) = pending.take() {
+        if limit.is_none_or(|limit| count_matches(&occurences) < limit) {
+            push_pending(finished, &mut occurences);
+        }
+    }
+    if let Some(limit) = limit {
+        truncate_matches(&mut occurences, limit);
+    }
 
-    return Some(FileData {
-        file_path,
-        file_tokens,
-    });
+    if occurences.is_empty() {
+        return None;
+    }
+
+    return Some(occurences);
+}
+
+// adds occurences in tokens of the line, returns false once `limit` matches are found;
+// tokens are matched as slices of the line, only the ones with a match are copied out of it
+fn search_line<M: Matcher + ?Sized>(line: &str, line_num: usize, opts: &SearchOpts, matcher: &M, limit: Option<usize>, occurences: &mut Vec<Occurence>) -> bool {
+    if opts.prefilter_lines && matcher.find_at(line.as_bytes(), 0).is_none() {
+        return true;
+    }
+
+    if opts.line_tokens {
+        add_occurence(line, line_num, opts.line_numbers, matcher, occurences);
+    } else {
+        for token_span in token_spans(line.as_bytes()) {
+            add_occurence(&line[token_span], line_num, opts.line_numbers, matcher, occurences);
+        }
+    }
+
+    if let Some(limit) = limit {
+        if count_matches(occurences) >= limit {
+            truncate_matches(occurences, limit);
+            return false;
+        }
+    }
+
+    return true;
+}
+
+// limits count matches, not occurences: a token may hold a few of them
+fn count_matches(occurences: &[Occurence]) -> usize {
+    return occurences.iter().map(|occurence| occurence.matches_count()).sum();
+}
+
+// keeps only the first `limit` matches, the last kept occurence may lose some of its matches
+fn truncate_matches(occurences: &mut Vec<Occurence>, limit: usize) {
+    let mut remaining = limit;
+    let mut kept: usize = 0;
+    for occurence in occurences.iter_mut() {
+        if remaining == 0 {
+            break;
+        }
+        if occurence.matches.len() > remaining {
+            occurence.matches.truncate(remaining);
+            occurence.distances.truncate(remaining);
+        }
+        remaining -= occurence.matches_count();
+        kept += 1;
+    }
+    occurences.truncate(kept);
 }
 
-fn get_file_tokens(file_path: &str, quiet: bool, line_numbers: bool) -> Option<Vec<Token>> {
-    let mut file = match File::open(file_path) {
-        Ok(file) => file,
+fn open_file(file_path: &str, quiet: bool) -> std::io::Result<File> {
+    match File::open(file_path) {
+        Ok(f) => return Ok(f),
         Err(err) => match err.kind() {
             ErrorKind::PermissionDenied => {
                 if !quiet {
                     eprintln!("Permission denied for file access at path: {}", file_path);
                 }
-                return None;
+                return Err(err)
             },
             ErrorKind::NotFound => {
                 if !quiet {
                     eprintln!("File wasn't found at path: {}", file_path);
                 }
-                return None;
+                return Err(err)
             },
             ErrorKind::IsADirectory => {
                 if !quiet {
                     eprintln!("Directory found at path, not a file: {}", file_path);
+                    eprintln!("Consider specify flags for directory search: -d,-r");
                 }
-                return None;
+                return Err(err)
             },
             _ => {
-                eprintln!("Unexpected error for file access at path: {}", file_path);
-                return None;
+                if !quiet {
+                    eprintln!("Unexpected error when opening a file: {}", file_path);
+                }
+                return Err(err)
             }
         }
-    };
+    }
+}
+
+// reader of raw file contents as utf-8 text, decompressed and transcoded if needed
+fn text_reader(mut reader: Box<dyn BufRead + Send>, opts: &SearchOpts) -> std::io::Result<Box<dyn BufRead>> {
+    if opts.decompress {
+        reader = decompressing_reader(reader)?;
+    }
+
+    return utf8_reader(reader, opts.encoding, opts.lossy);
+}
+
+fn read_file_contents(file_path: &str, quiet: bool) -> Option<String> {
+    let mut file = open_file(file_path, quiet).ok()?;
 
     let mut contents_buff = String::new();
 
-    match File::read_to_string(&mut file, &mut contents_buff) {
-        Ok(_) => {
-            let file_tokens = parse_to_tokens(&contents_buff, line_numbers);
-            return Some(file_tokens);
+    if let Err(err) = file.read_to_string(&mut contents_buff) {
+        report_read_error(file_path, err, quiet);
+        return None;
+    }
+
+    return Some(contents_buff);
+}
+
+fn report_read_error(file_path: &str, err: std::io::Error, quiet: bool) {
+    if quiet {
+        return;
+    }
+
+    match err.kind() {
+        ErrorKind::PermissionDenied => {
+            eprintln!("Permission denied for file access at path: {}", file_path);
         },
-        Err(err) => {
-            if !quiet {
-                eprintln!("Error while reading a file from path: {}", file_path);
-                eprintln!("Error: {}", err);
-            }
-            return None
+        ErrorKind::IsADirectory => {
+            eprintln!("Directory found at path, not a file: {}", file_path);
+            eprintln!("Consider specify flags for directory search: -d,-r");
+        },
+        _ => {
+            eprintln!("Error while reading a file from path: {}", file_path);
+            eprintln!("Error: {}", err);
         }
     }
 }
 
-fn parse_to_tokens(file_contents: &String, line_numbers: bool) -> Vec<Token> {
-    let mut tokens = Vec::<Token>::with_capacity(file_contents.len() / 5);
+fn add_occurence<M: Matcher + ?Sized>(token: &str, line_num: usize, line_numbers: bool, matcher: &M, occurences: &mut Vec<Occurence>) {
+    let matches: Vec<Match> = find_iter(matcher, token.as_bytes()).collect();
+    if !matches.is_empty() {
+        let distances = matches.iter().filter_map(|found| matcher.match_distance(token.as_bytes(), *found)).collect();
+        occurences.push(Occurence {
+            token: make_token(token.to_string(), line_num, line_numbers),
+            matches,
+            distances,
+        });
+    }
+}
+
+// byte ranges of whitespace separated tokens of a line
+fn token_spans(line: &[u8]) -> TokenSpans<'_> {
+    return TokenSpans {
+        line,
+        pos: 0,
+    };
+}
+
+struct TokenSpans<'a> {
+    line:   &'a [u8],
+    pos:    usize,
+}
+
+impl Iterator for TokenSpans<'_> {
+    type Item = Range<usize>;
 
-    let it: Vec<char> = file_contents.chars().collect();
-    let len = it.len();
-    let mut ind: usize = 0;
-    let mut curr_slice: &[char];
-    let mut curr_slice_start: usize;
-    let mut curr_line_num: usize = 0;
+    fn next(&mut self) -> Option<Range<usize>> {
+        let len = self.line.len();
 
-    while ind < len {
         // skipping whitespace
-        while ind < len && char::is_whitespace(it[ind]) {
-            if line_numbers && it[ind] == '\n' {
-                curr_line_num += 1;
+        while self.pos < len {
+            let width = whitespace_width(self.line, self.pos);
+            if width == 0 {
+                break;
             }
-            ind += 1;
+            self.pos += width;
         }
 
-        if ind >= len {
-            break;
+        if self.pos >= len {
+            return None;
         }
 
-        curr_slice_start = ind;
-
-        while ind < len && !char::is_whitespace(it[ind]) {
-            ind += 1;
+        let token_start = self.pos;
+        while self.pos < len && whitespace_width(self.line, self.pos) == 0 {
+            self.pos += utf8_char_width(self.line, self.pos).max(1);
         }
 
-        if ind - curr_slice_start != 0 {
-            curr_slice = &it[curr_slice_start..ind];
-            if line_numbers {
-                let new_token = TokenWithLine {
-                    contents: char_slice_to_str(curr_slice),
-                    line_num: curr_line_num,
-                };
-                tokens.push(Token::TokenStrLine(new_token));
-            } else {
-                tokens.push(Token::TokenStr(char_slice_to_str(curr_slice)));
-            }
-        }
+        return Some(token_start..self.pos);
     }
-
-    return tokens;
 }
 
-pub fn char_slice_to_str(char_slice: &[char]) -> String {
-    let mut s = String::with_capacity(char_slice.len());
+// width in bytes of a whitespace char at `pos`, 0 for other chars;
+// only non-ascii chars are decoded, for unicode spaces like U+00A0
+fn whitespace_width(bytes: &[u8], pos: usize) -> usize {
+    let byte = bytes[pos];
+    if byte.is_ascii() {
+        return if char::from(byte).is_whitespace() { 1 } else { 0 };
+    }
+
+    match decode_utf8_char(&bytes[pos..]) {
+        Some((c, width)) if c.is_whitespace() => return width,
+        _ => return 0,
+    }
+}
 
-    for c in char_slice {
-        s.push(*c);
+fn make_token(contents: String, line_num: usize, line_numbers: bool) -> Token {
+    if line_numbers {
+        return Token::TokenStrLine(TokenWithLine {
+            contents,
+            line_num,
+        });
     }
 
-    return s;
+    return Token::TokenStr(contents);
 }
 
-fn filter_path(path: &String, exclude_paths: &Vec<String>) -> bool {
+pub fn filter_path(path: &str, exclude_paths: &[String]) -> bool {
     for excl_path in exclude_paths.iter() {
         if path.contains(excl_path) {
             return false;
@@ -347,3 +1640,69 @@ fn filter_path(path: &String, exclude_paths: &Vec<String>) -> bool {
 
     return true;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::str_pattern_match::LiteralMatcher;
+
+    // empty directory of the test, unique for the process
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("minigrep-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        return dir;
+    }
+
+    #[test]
+    fn limits_count_matches_not_lines() {
+        let dir = test_dir("limits");
+        std::fs::write(dir.join("a.txt"), "x x x\nx\n").unwrap();
+        std::fs::write(dir.join("b.txt"), "x x\n").unwrap();
+
+        let mut user_input = UserInput::new_empty();
+        user_input.search_path = dir.to_string_lossy().into_owned();
+        user_input.opt_flags = vec![OptFlag::Dir, OptFlag::OnlyMatching, OptFlag::Quiet];
+        user_input.max_count = Some(2);
+        user_input.max_total = Some(3);
+
+        // a line holds all its matches with -o, the limit may cut it in the middle
+        let (file_search_data, stats) = do_search_with_stats(&user_input, &LiteralMatcher::new("x"));
+        let counts: Vec<(usize, usize)> = file_search_data.iter()
+            .map(|file_d| (file_d.occurences.len(), count_matches(&file_d.occurences)))
+            .collect();
+        assert_eq!(counts, vec![(1, 2), (1, 1)]);
+        assert_eq!(stats.matches, 3);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sorted_walk_follows_byte_order_of_paths() {
+        let dir = test_dir("walk-order");
+        std::fs::create_dir(dir.join("a")).unwrap();
+        for name in ["a/x", "a.txt", "a-b", "a0"] {
+            std::fs::write(dir.join(name), "text").unwrap();
+        }
+
+        let mut user_input = UserInput::new_empty();
+        user_input.opt_flags = vec![OptFlag::Recursive];
+        user_input.sort_by = Some(SortKey::Path);
+        let root = dir.to_string_lossy().into_owned();
+        let walk = |user_input: &UserInput| {
+            let mut paths = Vec::<String>::new();
+            DirWalker::from_user_input(user_input).walk(&root, &mut |file_path| {
+                paths.push(relative_path(&file_path, &root));
+                return true;
+            });
+            return paths;
+        };
+
+        // '-' < '.' < '/' < '0', a directory sorts as its name followed by '/'
+        assert_eq!(walk(&user_input), vec!["a-b", "a.txt", "a/x", "a0"]);
+        user_input.sort_descending = true;
+        assert_eq!(walk(&user_input), vec!["a0", "a/x", "a.txt", "a-b"]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,181 @@
+use std::collections::HashSet;
+
+// shell-like glob matching of file paths:
+// `*` matches any characters except '/', `**` matches across directories, `?` matches a single character,
+// `[abc]`, `[a-z]` and `[!abc]` match a character class;
+// a pattern without '/' is matched against the file name only, like in .gitignore,
+// callers pass paths relative to the directory patterns are written for
+pub fn glob_match(pattern: &str, path: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+
+    let text = if pattern.contains(&'/') {
+        path
+    } else {
+        path.rsplit('/').next().unwrap_or(path)
+    };
+    let text: Vec<char> = text.chars().collect();
+
+    return match_from(&pattern, &text, &mut HashSet::new());
+}
+
+pub fn glob_match_any(patterns: &[String], path: &str) -> bool {
+    return patterns.iter().any(|pattern| glob_match(pattern, path));
+}
+
+// `pattern` and `text` are always suffixes of the whole ones, so their lengths identify a state;
+// states which failed once are not tried again, otherwise a few `*` or `**` take exponential time
+fn match_from(pattern: &[char], text: &[char], failed: &mut HashSet<(usize, usize)>) -> bool {
+    let state = (pattern.len(), text.len());
+    if failed.contains(&state) {
+        return false;
+    }
+
+    let matched = match_here(pattern, text, failed);
+    if !matched {
+        failed.insert(state);
+    }
+    return matched;
+}
+
+fn match_here(pattern: &[char], text: &[char], failed: &mut HashSet<(usize, usize)>) -> bool {
+    match pattern.first() {
+        None => return text.is_empty(),
+        Some('*') if pattern.get(1) == Some(&'*') => {
+            let rest = &pattern[2..];
+            // `**/` also matches no directories at all
+            if rest.first() == Some(&'/') && match_from(&rest[1..], text, failed) {
+                return true;
+            }
+            return (0..=text.len()).any(|skip| match_from(rest, &text[skip..], failed));
+        },
+        Some('*') => {
+            let rest = &pattern[1..];
+            for skip in 0..=text.len() {
+                if match_from(rest, &text[skip..], failed) {
+                    return true;
+                }
+                if skip < text.len() && text[skip] == '/' {
+                    break;
+                }
+            }
+            return false;
+        },
+        Some('?') => {
+            return match text.first() {
+                Some(&ch) if ch != '/' => match_from(&pattern[1..], &text[1..], failed),
+                _ => false,
+            };
+        },
+        Some('[') => {
+            if let Some((class_len, negated)) = parse_class(pattern) {
+                return match text.first() {
+                    Some(&ch) if ch != '/' => {
+                        let in_class = class_contains(&pattern[1..class_len - 1], ch);
+                        in_class != negated && match_from(&pattern[class_len..], &text[1..], failed)
+                    },
+                    _ => false,
+                };
+            }
+            // unclosed bracket is a literal character
+            return text.first() == Some(&'[') && match_from(&pattern[1..], &text[1..], failed);
+        },
+        Some(&ch) => return text.first() == Some(&ch) && match_from(&pattern[1..], &text[1..], failed),
+    }
+}
+
+// length of a character class at the start of `pattern` including brackets, and whether it's negated
+fn parse_class(pattern: &[char]) -> Option<(usize, bool)> {
+    let negated = pattern.get(1) == Some(&'!');
+    let first_ind = if negated { 2 } else { 1 };
+
+    // ']' right after the opening bracket is a member of the class
+    let close_ind = pattern.iter()
+        .enumerate()
+        .skip(first_ind + 1)
+        .find(|(_, ch)| **ch == ']')
+        .map(|(ind, _)| ind)?;
+
+    return Some((close_ind + 1, negated));
+}
+
+fn class_contains(class: &[char], ch: char) -> bool {
+    let class = class.strip_prefix(&['!']).unwrap_or(class);
+    let mut ind = 0;
+
+    while ind < class.len() {
+        if ind + 2 < class.len() && class[ind + 1] == '-' {
+            if class[ind] <= ch && ch <= class[ind + 2] {
+                return true;
+            }
+            ind += 3;
+        } else {
+            if class[ind] == ch {
+                return true;
+            }
+            ind += 1;
+        }
+    }
+
+    return false;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_wildcards_within_a_name() {
+        assert!(glob_match("*.rs", "src/main.rs"));
+        assert!(glob_match("ma?n.*", "main.rs"));
+        assert!(glob_match("*", ".hidden"));
+        assert!(!glob_match("*.rs", "main.rs.bak"));
+        assert!(!glob_match("ma?n.rs", "man.rs"));
+        assert!(!glob_match("src/*.rs", "src/bin/main.rs"));
+        assert!(!glob_match("src?main.rs", "src/main.rs"));
+    }
+
+    #[test]
+    fn matches_character_classes() {
+        assert!(glob_match("[abc].txt", "b.txt"));
+        assert!(glob_match("file[0-9].log", "file7.log"));
+        assert!(glob_match("[a-cx-z]", "y"));
+        assert!(glob_match("[]x]", "]"));
+        assert!(!glob_match("[abc].txt", "d.txt"));
+        assert!(!glob_match("file[0-9].log", "filex.log"));
+        assert!(!glob_match("a[/]b", "a/b"));
+        // unclosed bracket is a literal
+        assert!(glob_match("[ab", "[ab"));
+        assert!(!glob_match("[ab", "a"));
+    }
+
+    #[test]
+    fn matches_negated_classes() {
+        assert!(glob_match("[!abc].txt", "d.txt"));
+        assert!(glob_match("[!0-9]*", "x1"));
+        assert!(!glob_match("[!abc].txt", "a.txt"));
+        assert!(!glob_match("[!0-9]*", "1x"));
+    }
+
+    #[test]
+    fn double_star_matches_across_directories() {
+        assert!(glob_match("**/*.rs", "main.rs"));
+        assert!(glob_match("**/*.rs", "src/bin/main.rs"));
+        assert!(glob_match("src/**/main.rs", "src/main.rs"));
+        assert!(glob_match("src/**/main.rs", "src/a/b/main.rs"));
+        assert!(glob_match("src/**", "src/a/b"));
+        assert!(glob_match("a/**b", "a/x/yb"));
+        assert!(!glob_match("src/**/main.rs", "lib/src/main.rs"));
+        assert!(!glob_match("src/**/main.rs", "src/a/main.rsx"));
+        assert!(!glob_match("**/*.rs", "src/main.c"));
+    }
+
+    #[test]
+    fn backtracking_stays_fast() {
+        let path = format!("{}/c", "a/".repeat(40));
+        let pattern = "**/a**/a**/a**/a**/a**/b";
+        let started = std::time::Instant::now();
+        assert!(!glob_match(pattern, &path));
+        assert!(!glob_match("*a*a*a*a*a*a*b", &"a".repeat(100)));
+        assert!(started.elapsed() < std::time::Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,435 @@
+use std::collections::HashMap;
+use std::fs::{File, Metadata};
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use crate::encoding::detect_bom;
+
+// index lives in the root of the indexed directory, hidden files are not searched by default
+pub const INDEX_FILE_NAME: &str = ".minigrep-index";
+
+const INDEX_MAGIC: &[u8] = b"MGIDX1\n";
+const READ_BUFF_SIZE: usize = 64 * 1024;
+// every trigram is 3 bytes packed into the lower bits of u32
+const TRIGRAM_COUNT: usize = 1 << 24;
+
+pub fn index_path(root: &str) -> PathBuf {
+    return Path::new(root).join(INDEX_FILE_NAME);
+}
+
+// trigrams of files under a directory, used to skip files which can't contain a pattern;
+// bytes are folded to ascii lowercase, so the same index serves searches with and without -i
+pub struct TrigramIndex {
+    root:       PathBuf,
+    entries:    HashMap<String, IndexEntry>,
+}
+
+struct IndexEntry {
+    mtime_secs:     i64,
+    mtime_nanos:    i64,
+    size:           u64,
+    // None for files which text is not their raw bytes (utf-16), those are always searched
+    trigrams:       Option<Vec<u32>>,
+}
+
+impl IndexEntry {
+    fn is_fresh(&self, metadata: &Metadata) -> bool {
+        return self.mtime_secs == metadata.mtime() && self.mtime_nanos == metadata.mtime_nsec() && self.size == metadata.size();
+    }
+}
+
+// trigrams every file has to contain to be worth searching, for any of the patterns
+pub struct TrigramQuery {
+    alternatives: Vec<Vec<u32>>,
+}
+
+impl TrigramQuery {
+    pub fn new(patterns: &[String], ignore_case: bool) -> Self {
+        let alternatives = patterns.iter()
+            .map(|pattern| pattern_trigrams(pattern.as_bytes(), ignore_case))
+            .collect();
+        return Self { alternatives };
+    }
+}
+
+impl TrigramIndex {
+    pub fn new(root: &str) -> Self {
+        Self {
+            root:       PathBuf::from(root),
+            entries:    HashMap::new(),
+        }
+    }
+
+    pub fn files_count(&self) -> usize {
+        return self.entries.len();
+    }
+
+    pub fn load(root: &str) -> std::io::Result<Self> {
+        let mut reader = BufReader::new(File::open(index_path(root))?);
+
+        let mut magic = [0u8; INDEX_MAGIC.len()];
+        reader.read_exact(&mut magic)?;
+        if magic != INDEX_MAGIC {
+            return Err(std::io::Error::new(ErrorKind::InvalidData, "not a minigrep index or an index of another version"));
+        }
+
+        let mut index = Self::new(root);
+        let entries_count = read_u64(&mut reader)?;
+        for _ in 0..entries_count {
+            let path_len = read_u64(&mut reader)? as usize;
+            let mut path = vec![0u8; path_len];
+            reader.read_exact(&mut path)?;
+            let path = String::from_utf8(path).map_err(|err| std::io::Error::new(ErrorKind::InvalidData, err))?;
+
+            let mtime_secs = read_u64(&mut reader)? as i64;
+            let mtime_nanos = read_u64(&mut reader)? as i64;
+            let size = read_u64(&mut reader)?;
+
+            let mut has_trigrams = [0u8; 1];
+            reader.read_exact(&mut has_trigrams)?;
+            let trigrams = if has_trigrams[0] == 1 {
+                let trigrams_count = read_u64(&mut reader)? as usize;
+                let mut trigrams = Vec::<u32>::with_capacity(trigrams_count);
+                for _ in 0..trigrams_count {
+                    trigrams.push(read_u32(&mut reader)?);
+                }
+                Some(trigrams)
+            } else {
+                None
+            };
+
+            index.entries.insert(path, IndexEntry { mtime_secs, mtime_nanos, size, trigrams });
+        }
+
+        return Ok(index);
+    }
+
+    // written into a temporary file first, so searches never see a half written index
+    pub fn save(&self) -> std::io::Result<()> {
+        let path = index_path(&self.root.to_string_lossy());
+        let tmp_path = path.with_extension("tmp");
+
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        writer.write_all(INDEX_MAGIC)?;
+        writer.write_all(&(self.entries.len() as u64).to_le_bytes())?;
+
+        for (path, entry) in self.entries.iter() {
+            writer.write_all(&(path.len() as u64).to_le_bytes())?;
+            writer.write_all(path.as_bytes())?;
+            writer.write_all(&entry.mtime_secs.to_le_bytes())?;
+            writer.write_all(&entry.mtime_nanos.to_le_bytes())?;
+            writer.write_all(&entry.size.to_le_bytes())?;
+
+            match &entry.trigrams {
+                Some(trigrams) => {
+                    writer.write_all(&[1])?;
+                    writer.write_all(&(trigrams.len() as u64).to_le_bytes())?;
+                    for trigram in trigrams.iter() {
+                        writer.write_all(&trigram.to_le_bytes())?;
+                    }
+                },
+                None => writer.write_all(&[0])?,
+            }
+        }
+
+        writer.into_inner().map_err(|err| err.into_error())?.sync_all()?;
+        std::fs::rename(&tmp_path, &path)?;
+        return Ok(());
+    }
+
+    // adds the file, taking its entry from `previous` if the file hasn't changed since;
+    // returns whether the file had to be read
+    pub fn add_file(&mut self, file_path: &str, previous: &TrigramIndex, trigram_set: &mut TrigramSet) -> std::io::Result<bool> {
+        let key = self.key(file_path);
+        let metadata = std::fs::metadata(file_path)?;
+
+        if let Some(entry) = previous.entries.get(&key) {
+            if entry.is_fresh(&metadata) {
+                let entry = IndexEntry {
+                    mtime_secs:     entry.mtime_secs,
+                    mtime_nanos:    entry.mtime_nanos,
+                    size:           entry.size,
+                    trigrams:       entry.trigrams.clone(),
+                };
+                self.entries.insert(key, entry);
+                return Ok(false);
+            }
+        }
+
+        let entry = IndexEntry {
+            mtime_secs:     metadata.mtime(),
+            mtime_nanos:    metadata.mtime_nsec(),
+            size:           metadata.size(),
+            trigrams:       trigram_set.collect_file(file_path)?,
+        };
+        self.entries.insert(key, entry);
+        return Ok(true);
+    }
+
+    // false only if the file is indexed, unchanged since and lacks trigrams of every pattern
+    pub fn may_match(&self, file_path: &str, query: &TrigramQuery) -> bool {
+        let entry = match self.entries.get(&self.key(file_path)) {
+            Some(entry) => entry,
+            None => return true,
+        };
+        let trigrams = match &entry.trigrams {
+            Some(trigrams) => trigrams,
+            None => return true,
+        };
+        // errors are for the search to report
+        match std::fs::metadata(file_path) {
+            Ok(metadata) if entry.is_fresh(&metadata) => (),
+            _ => return true,
+        }
+
+        return query.alternatives.iter()
+            .any(|required| required.iter().all(|trigram| trigrams.binary_search(trigram).is_ok()));
+    }
+
+    // paths are kept relative to the root, so `-p dir` and `-p dir/` share the index
+    fn key(&self, file_path: &str) -> String {
+        let path = Path::new(file_path);
+        return path.strip_prefix(&self.root).unwrap_or(path).to_string_lossy().into_owned();
+    }
+}
+
+// distinct trigrams of a file, the bitmap is reused between files as it's 2MB
+pub struct TrigramSet {
+    seen:       Vec<u64>,
+    trigrams:   Vec<u32>,
+}
+
+impl TrigramSet {
+    pub fn new() -> Self {
+        Self {
+            seen:       vec![0; TRIGRAM_COUNT / 64],
+            trigrams:   Vec::new(),
+        }
+    }
+
+    fn collect_file(&mut self, file_path: &str) -> std::io::Result<Option<Vec<u32>>> {
+        let mut reader = BufReader::with_capacity(READ_BUFF_SIZE, File::open(file_path)?);
+
+        // text of such files is decoded before searching, their bytes say nothing about it
+        if let Some(encoding) = detect_bom(reader.fill_buf()?) {
+            if encoding != encoding_rs::UTF_8 {
+                return Ok(None);
+            }
+        }
+
+        let mut window: u32 = 0;
+        let mut window_len: usize = 0;
+        loop {
+            let buff = reader.fill_buf()?;
+            if buff.is_empty() {
+                break;
+            }
+
+            for byte in buff.iter() {
+                window = ((window << 8) | byte.to_ascii_lowercase() as u32) & (TRIGRAM_COUNT as u32 - 1);
+                window_len += 1;
+                if window_len >= 3 {
+                    let (word, bit) = (window as usize / 64, 1u64 << (window % 64));
+                    if self.seen[word] & bit == 0 {
+                        self.seen[word] |= bit;
+                        self.trigrams.push(window);
+                    }
+                }
+            }
+
+            let consumed = buff.len();
+            reader.consume(consumed);
+        }
+
+        for trigram in self.trigrams.iter() {
+            self.seen[*trigram as usize / 64] = 0;
+        }
+        let mut trigrams = std::mem::take(&mut self.trigrams);
+        trigrams.sort_unstable();
+
+        return Ok(Some(trigrams));
+    }
+}
+
+impl Default for TrigramSet {
+    fn default() -> Self {
+        return Self::new();
+    }
+}
+
+// with ignored case only ascii trigrams are reliable, other bytes may differ between cases of a char
+fn pattern_trigrams(pattern: &[u8], ignore_case: bool) -> Vec<u32> {
+    let mut trigrams: Vec<u32> = pattern.windows(3)
+        .filter(|bytes| !ignore_case || bytes.is_ascii())
+        .map(|bytes| {
+            return (bytes[0].to_ascii_lowercase() as u32) << 16
+                | (bytes[1].to_ascii_lowercase() as u32) << 8
+                | bytes[2].to_ascii_lowercase() as u32;
+        })
+        .collect();
+    trigrams.sort_unstable();
+    trigrams.dedup();
+    return trigrams;
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> std::io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    return Ok(u64::from_le_bytes(bytes));
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> std::io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    return Ok(u32::from_le_bytes(bytes));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // empty directory of the test, unique for the process
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("minigrep-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        return dir;
+    }
+
+    fn trigram(bytes: &[u8; 3]) -> u32 {
+        return (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+    }
+
+    fn query(pattern: &str, ignore_case: bool) -> TrigramQuery {
+        return TrigramQuery::new(&[pattern.to_string()], ignore_case);
+    }
+
+    // index of the files written into the directory
+    fn build(dir: &Path, files: &[(&str, &[u8])]) -> TrigramIndex {
+        let root = dir.to_string_lossy();
+        let mut index = TrigramIndex::new(&root);
+        let mut trigram_set = TrigramSet::new();
+        for (name, contents) in files {
+            let file_path = dir.join(name);
+            std::fs::write(&file_path, contents).unwrap();
+            index.add_file(&file_path.to_string_lossy(), &TrigramIndex::new(&root), &mut trigram_set).unwrap();
+        }
+        return index;
+    }
+
+    #[test]
+    fn extracts_pattern_trigrams() {
+        assert_eq!(pattern_trigrams(b"abcd", false), vec![trigram(b"abc"), trigram(b"bcd")]);
+        assert_eq!(pattern_trigrams(b"ABCABC", false), vec![trigram(b"abc"), trigram(b"bca"), trigram(b"cab")]);
+        assert_eq!(pattern_trigrams(b"ab", false), Vec::<u32>::new());
+        // "é" is 2 bytes, with -i only the ascii window is left
+        assert_eq!(pattern_trigrams("xéab".as_bytes(), false).len(), 3);
+        assert_eq!(pattern_trigrams("xéab".as_bytes(), true), Vec::<u32>::new());
+        assert_eq!(pattern_trigrams("éabc".as_bytes(), true), vec![trigram(b"abc")]);
+    }
+
+    #[test]
+    fn extracts_file_trigrams() {
+        let dir = test_dir("index-trigrams");
+        let mut trigram_set = TrigramSet::new();
+
+        let file_path = dir.join("text.txt");
+        std::fs::write(&file_path, "ABcab").unwrap();
+        let trigrams = trigram_set.collect_file(&file_path.to_string_lossy()).unwrap();
+        assert_eq!(trigrams, Some(vec![trigram(b"abc"), trigram(b"bca"), trigram(b"cab")]));
+
+        // the bitmap is cleared for the next file
+        std::fs::write(&file_path, "xy").unwrap();
+        assert_eq!(trigram_set.collect_file(&file_path.to_string_lossy()).unwrap(), Some(vec![]));
+
+        std::fs::write(&file_path, b"\xFF\xFEa\x00b\x00c\x00").unwrap();
+        assert_eq!(trigram_set.collect_file(&file_path.to_string_lossy()).unwrap(), None);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn never_skips_files_containing_the_pattern() {
+        let dir = test_dir("index-matches");
+        let text = "Hello, wörld! fn main() {}\n";
+        let index = build(&dir, &[("a.txt", text.as_bytes())]);
+        let file_path = dir.join("a.txt").to_string_lossy().into_owned();
+
+        for pattern in ["Hello", "wörld", "fn main()", "{}\n", "lo", ""] {
+            assert!(index.may_match(&file_path, &query(pattern, false)), "{}", pattern);
+        }
+        for pattern in ["HELLO", "WöRLD", "FN MAIN"] {
+            assert!(index.may_match(&file_path, &query(pattern, true)), "{}", pattern);
+        }
+        for pattern in ["goodbye", "Hello, world", "main {}"] {
+            assert!(!index.may_match(&file_path, &query(pattern, false)), "{}", pattern);
+        }
+        // a file matches if any of the patterns may match
+        let query = TrigramQuery::new(&["goodbye".to_string(), "main".to_string()], false);
+        assert!(index.may_match(&file_path, &query));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn searches_changed_and_unknown_files() {
+        let dir = test_dir("index-stale");
+        let index = build(&dir, &[("a.txt", b"old text"), ("utf16.txt", b"\xFF\xFEa\x00b\x00c\x00")]);
+        let file_path = dir.join("a.txt").to_string_lossy().into_owned();
+        assert!(!index.may_match(&file_path, &query("new", false)));
+
+        std::fs::write(&file_path, "new text, longer").unwrap();
+        assert!(index.may_match(&file_path, &query("new", false)));
+
+        let other_path = dir.join("b.txt").to_string_lossy().into_owned();
+        assert!(index.may_match(&other_path, &query("new", false)));
+        let utf16_path = dir.join("utf16.txt").to_string_lossy().into_owned();
+        assert!(index.may_match(&utf16_path, &query("xyz", false)));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn saved_index_loads_back() {
+        let dir = test_dir("index-round-trip");
+        let root = dir.to_string_lossy().into_owned();
+        let index = build(&dir, &[("a.txt", b"alpha beta"), ("b.txt", b"gamma"), ("utf16.txt", b"\xFF\xFEa\x00")]);
+        index.save().unwrap();
+
+        let loaded = TrigramIndex::load(&root).unwrap();
+        assert_eq!(loaded.files_count(), 3);
+        for (name, entry) in index.entries.iter() {
+            let loaded_entry = &loaded.entries[name];
+            assert_eq!(loaded_entry.trigrams, entry.trigrams);
+            assert_eq!((loaded_entry.mtime_secs, loaded_entry.mtime_nanos, loaded_entry.size), (entry.mtime_secs, entry.mtime_nanos, entry.size));
+        }
+
+        // unchanged files are taken from the previous index without reading them
+        let mut updated = TrigramIndex::new(&root);
+        let a_path = dir.join("a.txt").to_string_lossy().into_owned();
+        assert!(!updated.add_file(&a_path, &loaded, &mut TrigramSet::new()).unwrap());
+        std::fs::write(&a_path, "alpha beta gamma").unwrap();
+        let mut updated = TrigramIndex::new(&root);
+        assert!(updated.add_file(&a_path, &loaded, &mut TrigramSet::new()).unwrap());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_foreign_and_broken_indexes() {
+        let dir = test_dir("index-magic");
+        let root = dir.to_string_lossy().into_owned();
+
+        std::fs::write(index_path(&root), b"MGIDX0\n\x00\x00\x00\x00\x00\x00\x00\x00").unwrap();
+        assert_eq!(TrigramIndex::load(&root).err().unwrap().kind(), ErrorKind::InvalidData);
+
+        let index = build(&dir, &[("a.txt", b"alpha beta")]);
+        index.save().unwrap();
+        let saved = std::fs::read(index_path(&root)).unwrap();
+        std::fs::write(index_path(&root), &saved[..saved.len() - 2]).unwrap();
+        assert_eq!(TrigramIndex::load(&root).err().unwrap().kind(), ErrorKind::UnexpectedEof);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,16 @@
+// explicit returns are the code style of this project
+#![allow(clippy::needless_return, clippy::unused_unit)]
+
+pub mod archive;
+pub mod cli_input;
+pub mod cli_output;
+pub mod decompress;
+pub mod encoding;
+pub mod glob;
+pub mod index;
+pub mod preprocess;
+pub mod query;
+pub mod str_pattern_match;
+pub mod fs_related;
+pub mod tui;
+pub mod watch;
@@ -1,11 +1,15 @@
-use self::cli_input::OptFlag;
-use self::cli_output::{print_occurences_in_file, write_occurences_to_output_file};
-use self::fs_related::do_search;
+// explicit returns are the code style of this project
+#![allow(clippy::needless_return, clippy::unused_unit)]
 
-mod cli_input;
-mod cli_output;
-mod str_pattern_match;
-mod fs_related;
+use std::time::Instant;
+
+use minigrep::cli_input::{self, OptFlag};
+use minigrep::cli_output::{self, print_occurences_in_file, write_occurences_to_output_file};
+use minigrep::fs_related::{self, do_replace, do_search_with_stats};
+use minigrep::index::index_path;
+use minigrep::str_pattern_match::{self, build_matcher};
+use minigrep::tui::Tui;
+use minigrep::watch::Watcher;
 
 fn main() {
     let args: Vec<String> = std::env::args().collect();
@@ -20,19 +24,148 @@ fn main() {
         std::process::exit(0);
     }
 
-    let file_data = do_search(&user_input);
+    // building an index is a separate command, no search is done
+    if user_input.has_opt_flag(OptFlag::BuildIndex) {
+        run_build_index(&user_input);
+        return;
+    }
+
+    // the pattern may be typed in the interface itself
+    if user_input.has_opt_flag(OptFlag::Interactive) {
+        run_interactive(&user_input);
+        return;
+    }
+
+    if user_input.search_patterns.is_empty() && user_input.query.is_none() {
+        eprintln!("Search pattern wasn't provided, use -s $pattern");
+        cli_output::print_help_info();
+        std::process::exit(1);
+    }
+
+    if !user_input.search_patterns.is_empty() && user_input.query.is_some() {
+        eprintln!("Patterns are given both with -s and --query, put all of them into the query");
+        std::process::exit(1);
+    }
+
+    let matcher = build_matcher(&user_input);
+
+    if let Some(replacement) = &user_input.replacement {
+        run_replace(&user_input, &matcher, replacement);
+        return;
+    }
+
+    if user_input.has_opt_flag(OptFlag::Follow) {
+        run_follow(&user_input, &matcher);
+        return;
+    }
+
+    let started = Instant::now();
+    let (file_data, stats) = do_search_with_stats(&user_input, &matcher);
 
     match &user_input.output_file_path {
         Some(output_file_path) => {
-            for file_d in file_data {
-                write_occurences_to_output_file(&user_input.search_pattern, file_d, output_file_path, &user_input);
+            for file_d in file_data.iter() {
+                write_occurences_to_output_file(file_d, output_file_path, &user_input);
             }
         },
         None => {
-            for file_d in file_data {
-                print_occurences_in_file(&user_input.search_pattern, file_d, &user_input);
+            for file_d in file_data.iter() {
+                print_occurences_in_file(file_d, &user_input);
+            }
+        }
+    }
+
+    // elapsed time includes printing, as a slow output slows the whole run
+    if user_input.has_opt_flag(OptFlag::Stats) || user_input.has_opt_flag(OptFlag::StatsJson) {
+        match &user_input.output_file_path {
+            Some(output_file_path) => cli_output::write_stats_to_output_file(&stats, started.elapsed(), output_file_path, &user_input),
+            None => cli_output::print_stats(&stats, started.elapsed(), &user_input),
+        }
+    }
+
+    if user_input.has_opt_flag(OptFlag::Watch) {
+        run_watch(&user_input, &matcher, file_data);
+    }
+}
+
+fn run_replace(user_input: &cli_input::UserInput, matcher: &dyn str_pattern_match::Matcher, replacement: &str) {
+    let replace_data = do_replace(user_input, matcher, replacement);
+
+    // without --in-place nothing is written, only a diff of would-be changes is shown
+    if !user_input.has_opt_flag(OptFlag::InPlace) {
+        for replace_d in replace_data.iter() {
+            match &user_input.output_file_path {
+                Some(output_file_path) => cli_output::write_replace_diff_to_output_file(replace_d, output_file_path),
+                None => cli_output::print_replace_diff(replace_d),
+            }
+        }
+        return;
+    }
+
+    let quiet = user_input.has_opt_flag(OptFlag::Quiet);
+    for replace_d in replace_data.iter() {
+        if fs_related::write_file_atomically(&replace_d.file_path, &replace_d.new_contents(), quiet) {
+            println!("{}: {} replacements", replace_d.file_path, replace_d.replacements);
+        }
+    }
+}
+
+fn run_build_index(user_input: &cli_input::UserInput) {
+    match fs_related::build_index(user_input) {
+        Ok((indexed_files, read_files)) => {
+            println!("{}: {} files indexed, {} of them read", index_path(&user_input.search_path).display(), indexed_files, read_files);
+            if !user_input.has_opt_flag(OptFlag::Recursive) && !user_input.has_opt_flag(OptFlag::Quiet) {
+                eprintln!("Files of subdirectories weren't indexed, consider specify -r to index them too");
             }
+        },
+        Err(err) => {
+            eprintln!("Index of {} couldn't be built", user_input.search_path);
+            eprintln!("Error: {}", err);
+            std::process::exit(1);
         }
     }
+}
 
+fn run_watch(user_input: &cli_input::UserInput, matcher: &dyn str_pattern_match::Matcher, file_data: Vec<fs_related::FileData>) {
+    let result = Watcher::new(user_input, matcher, file_data).and_then(|mut watcher| watcher.run());
+    if let Err(err) = result {
+        eprintln!("Watching {} failed", user_input.search_path);
+        eprintln!("Error: {}", err);
+        std::process::exit(1);
+    }
+}
+
+fn run_follow(user_input: &cli_input::UserInput, matcher: &dyn str_pattern_match::Matcher) {
+    if user_input.has_opt_flag(OptFlag::Recursive) || user_input.has_opt_flag(OptFlag::Dir) {
+        eprintln!("Only a single file can be followed, unspecify flags -d,-r or use --watch");
+        std::process::exit(1);
+    }
+
+    let result = fs_related::do_follow(user_input, matcher, &mut |file_d| {
+        match &user_input.output_file_path {
+            Some(output_file_path) => write_occurences_to_output_file(&file_d, output_file_path, user_input),
+            None => print_occurences_in_file(&file_d, user_input),
+        }
+    });
+    if let Err(err) = result {
+        eprintln!("Following {} failed", user_input.search_path);
+        eprintln!("Error: {}", err);
+        std::process::exit(1);
+    }
+}
+
+fn run_interactive(user_input: &cli_input::UserInput) {
+    let mut tui = match Tui::new(user_input) {
+        Ok(tui) => tui,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
+
+    if let Err(err) = tui.run() {
+        eprintln!("Interactive mode failed");
+        eprintln!("Error: {}", err);
+        std::process::exit(1);
+    }
 }
@@ -0,0 +1,59 @@
+use std::io::{BufReader, Read};
+use std::process::{Child, ChildStdout, Command, Stdio};
+
+// runs `command` with the file path as its only argument, stdout of the command is read instead of the file;
+// stderr is left to the terminal, so the command can report its own problems
+pub fn preprocessor_reader(command: &str, file_path: &str) -> std::io::Result<BufReader<PreprocessorReader>> {
+    let mut child = Command::new(command)
+        .arg(file_path)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|err| std::io::Error::new(err.kind(), format!("can't run preprocessor {}: {}", command, err)))?;
+
+    let stdout = child.stdout.take().expect("stdout of preprocessor is piped");
+    let reader = PreprocessorReader {
+        child,
+        stdout,
+        finished: false,
+    };
+    return Ok(BufReader::new(reader));
+}
+
+pub struct PreprocessorReader {
+    child:      Child,
+    stdout:     ChildStdout,
+    finished:   bool,
+}
+
+impl Read for PreprocessorReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.finished {
+            return Ok(0);
+        }
+
+        let read = self.stdout.read(buf)?;
+        if read > 0 || buf.is_empty() {
+            return Ok(read);
+        }
+
+        // output is over, a failed command means the output can't be trusted to be complete
+        self.finished = true;
+        let status = self.child.wait()?;
+        if !status.success() {
+            return Err(std::io::Error::other(format!("preprocessor failed with {}", status)));
+        }
+
+        return Ok(0);
+    }
+}
+
+impl Drop for PreprocessorReader {
+    // search may stop before the whole output is read, the command is not needed anymore then
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+}
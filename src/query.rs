@@ -0,0 +1,385 @@
+use crate::str_pattern_match::{Match, Matcher, MultiMatcher};
+
+const KEYWORD_AND: &str = "AND";
+const KEYWORD_OR: &str = "OR";
+const KEYWORD_NOT: &str = "NOT";
+
+// text a query is evaluated against
+#[derive(Clone, Copy, PartialEq)]
+pub enum QueryScope {
+    Line,
+    File,
+}
+
+// boolean expression over patterns, terms refer to patterns by their index
+pub enum QueryExpr {
+    Term(usize),
+    Not(Box<QueryExpr>),
+    And(Box<QueryExpr>, Box<QueryExpr>),
+    Or(Box<QueryExpr>, Box<QueryExpr>),
+}
+
+impl QueryExpr {
+    pub fn eval<F: Fn(usize) -> bool>(&self, term_matches: &F) -> bool {
+        return match self {
+            QueryExpr::Term(ind) => term_matches(*ind),
+            QueryExpr::Not(expr) => !expr.eval(term_matches),
+            QueryExpr::And(left, right) => left.eval(term_matches) && right.eval(term_matches),
+            QueryExpr::Or(left, right) => left.eval(term_matches) || right.eval(term_matches),
+        };
+    }
+
+    // terms which are not negated, only their matches are shown
+    fn collect_shown_terms(&self, negated: bool, shown: &mut Vec<usize>) {
+        match self {
+            QueryExpr::Term(ind) => {
+                if !negated && !shown.contains(ind) {
+                    shown.push(*ind);
+                }
+            },
+            QueryExpr::Not(expr) => expr.collect_shown_terms(!negated, shown),
+            QueryExpr::And(left, right) | QueryExpr::Or(left, right) => {
+                left.collect_shown_terms(negated, shown);
+                right.collect_shown_terms(negated, shown);
+            },
+        }
+    }
+}
+
+// parsed query, `patterns[i]` is the pattern of QueryExpr::Term(i)
+pub struct Query {
+    pub expr:       QueryExpr,
+    pub patterns:   Vec<String>,
+    // indices of patterns which are not negated
+    pub shown:      Vec<usize>,
+}
+
+#[derive(PartialEq)]
+enum QueryToken {
+    Word(String),
+    // quoted words are never keywords
+    Quoted(String),
+    LeftParen,
+    RightParen,
+}
+
+// grammar, from the lowest precedence:
+//   or   := and (OR and)*
+//   and  := not (AND? not)*      adjacent terms are joined by AND
+//   not  := NOT not | atom
+//   atom := ( or ) | pattern | "quoted pattern"
+pub fn parse_query(text: &str) -> Result<Query, String> {
+    let tokens = tokenize_query(text)?;
+    let mut parser = QueryParser {
+        tokens,
+        pos:        0,
+        patterns:   Vec::new(),
+    };
+
+    let expr = parser.parse_or()?;
+    if let Some(token) = parser.tokens.get(parser.pos) {
+        return Err(format!("unexpected {}", describe_token(token)));
+    }
+
+    // output consists of matches of not negated patterns, a query which holds while none of its patterns
+    // match (like 'a OR NOT b') would select lines with nothing to output; patterns only under NOT are
+    // most permissive when they don't match, so checking that case is enough
+    if expr.eval(&|_| false) {
+        return Err("query can hold with none of its patterns matching, every alternative needs a pattern which is not under NOT".to_string());
+    }
+
+    let mut shown = Vec::<usize>::new();
+    expr.collect_shown_terms(false, &mut shown);
+
+    return Ok(Query {
+        expr,
+        patterns:   parser.patterns,
+        shown,
+    });
+}
+
+fn tokenize_query(text: &str) -> Result<Vec<QueryToken>, String> {
+    let mut tokens = Vec::<QueryToken>::new();
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '(' => tokens.push(QueryToken::LeftParen),
+            ')' => tokens.push(QueryToken::RightParen),
+            '"' => {
+                // \" and \\ are the only escapes
+                let mut quoted = String::new();
+                let mut closed = false;
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => {
+                            closed = true;
+                            break;
+                        },
+                        '\\' if matches!(chars.peek(), Some('"') | Some('\\')) => quoted.push(chars.next().unwrap()),
+                        _ => quoted.push(c),
+                    }
+                }
+                if !closed {
+                    return Err("unclosed quote".to_string());
+                }
+                tokens.push(QueryToken::Quoted(quoted));
+            },
+            _ if c.is_whitespace() => (),
+            _ => {
+                let mut word = String::from(c);
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || c == '(' || c == ')' || c == '"' {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                tokens.push(QueryToken::Word(word));
+            }
+        }
+    }
+
+    return Ok(tokens);
+}
+
+fn describe_token(token: &QueryToken) -> String {
+    return match token {
+        QueryToken::Word(word) => format!("'{}'", word),
+        QueryToken::Quoted(quoted) => format!("\"{}\"", quoted),
+        QueryToken::LeftParen => "'('".to_string(),
+        QueryToken::RightParen => "')'".to_string(),
+    };
+}
+
+struct QueryParser {
+    tokens:     Vec<QueryToken>,
+    pos:        usize,
+    patterns:   Vec<String>,
+}
+
+impl QueryParser {
+    fn peek_keyword(&self, keyword: &str) -> bool {
+        return matches!(self.tokens.get(self.pos), Some(QueryToken::Word(word)) if word == keyword);
+    }
+
+    fn parse_or(&mut self) -> Result<QueryExpr, String> {
+        let mut expr = self.parse_and()?;
+        while self.peek_keyword(KEYWORD_OR) {
+            self.pos += 1;
+            expr = QueryExpr::Or(Box::new(expr), Box::new(self.parse_and()?));
+        }
+        return Ok(expr);
+    }
+
+    fn parse_and(&mut self) -> Result<QueryExpr, String> {
+        let mut expr = self.parse_not()?;
+        loop {
+            if self.peek_keyword(KEYWORD_AND) {
+                self.pos += 1;
+            } else {
+                // implicit AND between adjacent operands
+                match self.tokens.get(self.pos) {
+                    None | Some(QueryToken::RightParen) => break,
+                    _ if self.peek_keyword(KEYWORD_OR) => break,
+                    _ => (),
+                }
+            }
+            expr = QueryExpr::And(Box::new(expr), Box::new(self.parse_not()?));
+        }
+        return Ok(expr);
+    }
+
+    fn parse_not(&mut self) -> Result<QueryExpr, String> {
+        if self.peek_keyword(KEYWORD_NOT) {
+            self.pos += 1;
+            return Ok(QueryExpr::Not(Box::new(self.parse_not()?)));
+        }
+        return self.parse_atom();
+    }
+
+    fn parse_atom(&mut self) -> Result<QueryExpr, String> {
+        let token = match self.tokens.get(self.pos) {
+            Some(token) => token,
+            None => return Err("query ends where a pattern is expected".to_string()),
+        };
+
+        let pattern = match token {
+            QueryToken::LeftParen => {
+                self.pos += 1;
+                let expr = self.parse_or()?;
+                if self.tokens.get(self.pos) != Some(&QueryToken::RightParen) {
+                    return Err("unclosed parenthesis".to_string());
+                }
+                self.pos += 1;
+                return Ok(expr);
+            },
+            QueryToken::Word(word) if word == KEYWORD_AND || word == KEYWORD_OR || word == KEYWORD_NOT => {
+                return Err(format!("unexpected {}, quote it to search for it: \"{}\"", describe_token(token), word));
+            },
+            QueryToken::RightParen => return Err(format!("unexpected {}", describe_token(token))),
+            QueryToken::Word(pattern) | QueryToken::Quoted(pattern) => pattern.clone(),
+        };
+        self.pos += 1;
+
+        // same pattern used twice is matched once
+        let ind = match self.patterns.iter().position(|existing| *existing == pattern) {
+            Some(ind) => ind,
+            None => {
+                self.patterns.push(pattern);
+                self.patterns.len() - 1
+            }
+        };
+        return Ok(QueryExpr::Term(ind));
+    }
+}
+
+// matches of the query's not negated patterns, in a line (or a whole file, see `accepts_text`)
+// which satisfies the query
+pub struct QueryMatcher {
+    expr:       QueryExpr,
+    // a matcher for every pattern of the query
+    terms:      Vec<Box<dyn Matcher>>,
+    shown:      MultiMatcher,
+    scope:      QueryScope,
+}
+
+impl QueryMatcher {
+    // `shown` has matchers of `query.shown` patterns, in that order
+    pub fn new(query: Query, terms: Vec<Box<dyn Matcher>>, shown: Vec<Box<dyn Matcher>>, scope: QueryScope) -> Self {
+        Self {
+            expr:   query.expr,
+            terms,
+            shown:  MultiMatcher::new(shown),
+            scope,
+        }
+    }
+
+    fn satisfied_by(&self, text: &[u8]) -> bool {
+        return self.expr.eval(&|ind| self.terms[ind].find_at(text, 0).is_some());
+    }
+}
+
+impl Matcher for QueryMatcher {
+    fn find_at(&self, haystack: &[u8], start: usize) -> Option<Match> {
+        if self.scope == QueryScope::Line && !self.satisfied_by(haystack) {
+            return None;
+        }
+        return self.shown.find_at(haystack, start);
+    }
+
+    fn expand_match(&self, haystack: &[u8], found: Match, replacement: &[u8], dst: &mut Vec<u8>) {
+        self.shown.expand_match(haystack, found, replacement, dst);
+    }
+
+    fn match_distance(&self, haystack: &[u8], found: Match) -> Option<usize> {
+        return self.shown.match_distance(haystack, found);
+    }
+
+    fn accepts_text(&self, text: &[u8]) -> bool {
+        return self.scope == QueryScope::Line || self.satisfied_by(text);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // query written back with explicit parentheses, patterns by name
+    fn render(query: &Query) -> String {
+        fn render_expr(expr: &QueryExpr, patterns: &[String]) -> String {
+            return match expr {
+                QueryExpr::Term(ind) => patterns[*ind].clone(),
+                QueryExpr::Not(expr) => format!("!{}", render_expr(expr, patterns)),
+                QueryExpr::And(left, right) => format!("({} & {})", render_expr(left, patterns), render_expr(right, patterns)),
+                QueryExpr::Or(left, right) => format!("({} | {})", render_expr(left, patterns), render_expr(right, patterns)),
+            };
+        }
+        return render_expr(&query.expr, &query.patterns);
+    }
+
+    fn parsed(text: &str) -> String {
+        return render(&parse_query(text).unwrap_or_else(|err| panic!("{}: {}", text, err)));
+    }
+
+    fn parse_error(text: &str) -> String {
+        return match parse_query(text) {
+            Ok(query) => panic!("{} parsed as {}", text, render(&query)),
+            Err(err) => err,
+        };
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(parsed("a OR b AND c"), "(a | (b & c))");
+        assert_eq!(parsed("a AND b OR c"), "((a & b) | c)");
+        assert_eq!(parsed("a AND NOT b OR c"), "((a & !b) | c)");
+        assert_eq!(parsed("a AND (b OR c)"), "(a & (b | c))");
+        assert_eq!(parsed("a OR b OR c"), "((a | b) | c)");
+        assert_eq!(parsed("NOT NOT a"), "!!a");
+    }
+
+    #[test]
+    fn implicit_and() {
+        assert_eq!(parsed("a b"), "(a & b)");
+        assert_eq!(parsed("a b OR c"), "((a & b) | c)");
+        assert_eq!(parsed("a NOT b"), "(a & !b)");
+        assert_eq!(parsed("(a OR b) c"), "((a | b) & c)");
+        assert_eq!(parsed("a(b)"), "(a & b)");
+    }
+
+    #[test]
+    fn quoting_and_escapes() {
+        assert_eq!(parsed("\"connection reset\" db"), "(connection reset & db)");
+        assert_eq!(parsed("\"AND\" a"), "(AND & a)");
+        assert_eq!(parsed("\"(x)\""), "(x)");
+        assert_eq!(parsed(r#""say \"hi\"""#), "say \"hi\"");
+        assert_eq!(parsed(r#""back\\slash""#), "back\\slash");
+        // other backslashes are kept as they are, for regexes
+        assert_eq!(parsed(r#""\d+""#), "\\d+");
+        // keywords are case sensitive
+        assert_eq!(parsed("a and b"), "((a & and) & b)");
+    }
+
+    #[test]
+    fn repeated_patterns_are_shared() {
+        let query = parse_query("a OR (b AND a)").unwrap();
+        assert_eq!(query.patterns, vec!["a".to_string(), "b".to_string()]);
+        assert_eq!(query.shown, vec![0, 1]);
+
+        let query = parse_query("a AND NOT b").unwrap();
+        assert_eq!(query.shown, vec![0]);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(parse_error("\"unclosed"), "unclosed quote");
+        assert_eq!(parse_error("(a OR b"), "unclosed parenthesis");
+        assert_eq!(parse_error("a)"), "unexpected ')'");
+        assert_eq!(parse_error(")"), "unexpected ')'");
+        assert_eq!(parse_error(""), "query ends where a pattern is expected");
+        assert_eq!(parse_error("a AND"), "query ends where a pattern is expected");
+        assert_eq!(parse_error("OR a"), "unexpected 'OR', quote it to search for it: \"OR\"");
+        assert_eq!(parse_error("a AND AND b"), "unexpected 'AND', quote it to search for it: \"AND\"");
+    }
+
+    #[test]
+    fn rejects_queries_without_shown_matches() {
+        assert!(parse_error("NOT a").starts_with("query can hold"));
+        assert!(parse_error("a OR NOT b").starts_with("query can hold"));
+        assert!(parse_error("NOT a AND NOT b").starts_with("query can hold"));
+        assert_eq!(parsed("(a OR NOT b) AND c"), "((a | !b) & c)");
+        assert_eq!(parsed("NOT a AND b"), "(!a & b)");
+    }
+
+    #[test]
+    fn eval() {
+        let query = parse_query("a AND NOT b OR c").unwrap();
+        let eval = |matching: &[&str]| query.expr.eval(&|ind| matching.contains(&query.patterns[ind].as_str()));
+        assert!(eval(&["a"]));
+        assert!(!eval(&["a", "b"]));
+        assert!(eval(&["a", "b", "c"]));
+        assert!(eval(&["c"]));
+        assert!(!eval(&[]));
+    }
+}
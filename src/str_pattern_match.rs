@@ -1,98 +1,982 @@
-use std::ops::Sub;
+use memchr::{memchr2, memmem};
+use regex::bytes::{Regex, RegexBuilder};
 
+use crate::cli_input::{OptFlag, UserInput};
 use crate::fs_related::Token;
+use crate::query::{parse_query, QueryMatcher};
 
-const UP_TO_LOW_CASE_OFFSET_ASCII: u8 = b'a' - b'A';
+// byte span of a single match inside of a haystack, `end` is exclusive
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Match {
+    pub start: usize,
+    pub end: usize,
+}
 
-pub fn find_occurences(tokens: Vec<Token>, pattern: &String, ignore_case: bool) -> Vec<Token> {
-    let mut occurences = Vec::<Token>::with_capacity(tokens.len() / 3);
- 
-    for token in tokens {
-        match token {
-            Token::TokenStr(ref token_str) => {
-                if match_str(&token_str, pattern, ignore_case) {
-                    occurences.push(token);
-                }
-            },
-            Token::TokenStrLine(ref token_line) => {
-                if match_str(&token_line.contents, pattern, ignore_case) {
-                    occurences.push(token);
-                }
+// search strategy, every new kind of matching should only implement this trait
+// to be usable by the walker and the printer
+pub trait Matcher {
+    // leftmost match in `haystack` which starts at `start` byte offset or later
+    fn find_at(&self, haystack: &[u8], start: usize) -> Option<Match>;
+
+    // appends replacement of the match `found` to `dst`,
+    // strategies with capture groups expand references to them in `replacement`
+    fn expand_match(&self, _haystack: &[u8], _found: Match, replacement: &[u8], dst: &mut Vec<u8>) {
+        dst.extend_from_slice(replacement);
+    }
+
+    // edit distance between the pattern and the match `found`, only approximate strategies have one
+    fn match_distance(&self, _haystack: &[u8], _found: Match) -> Option<usize> {
+        return None;
+    }
+
+    // whether matches of a file with the whole text `text` are worth searching,
+    // only queries evaluated per file check anything here
+    fn accepts_text(&self, _text: &[u8]) -> bool {
+        return true;
+    }
+}
+
+impl<M: Matcher + ?Sized> Matcher for Box<M> {
+    fn find_at(&self, haystack: &[u8], start: usize) -> Option<Match> {
+        return (**self).find_at(haystack, start);
+    }
+
+    fn expand_match(&self, haystack: &[u8], found: Match, replacement: &[u8], dst: &mut Vec<u8>) {
+        (**self).expand_match(haystack, found, replacement, dst);
+    }
+
+    fn match_distance(&self, haystack: &[u8], found: Match) -> Option<usize> {
+        return (**self).match_distance(haystack, found);
+    }
+
+    fn accepts_text(&self, text: &[u8]) -> bool {
+        return (**self).accepts_text(text);
+    }
+}
+
+// iterator over all non-overlapping matches in a haystack
+pub struct MatchIter<'m, 'h, M: Matcher + ?Sized> {
+    matcher:    &'m M,
+    haystack:   &'h [u8],
+    pos:        usize,
+}
+
+impl<M: Matcher + ?Sized> Iterator for MatchIter<'_, '_, M> {
+    type Item = Match;
+
+    fn next(&mut self) -> Option<Match> {
+        if self.pos > self.haystack.len() {
+            return None;
+        }
+
+        let found = self.matcher.find_at(self.haystack, self.pos)?;
+        if found.end == found.start {
+            // empty match, step over the next char so we don't loop forever
+            self.pos = found.end + utf8_char_width(self.haystack, found.end).max(1);
+        } else {
+            self.pos = found.end;
+        }
+
+        return Some(found);
+    }
+}
+
+pub fn find_iter<'m, 'h, M: Matcher + ?Sized>(matcher: &'m M, haystack: &'h [u8]) -> MatchIter<'m, 'h, M> {
+    return MatchIter {
+        matcher,
+        haystack,
+        pos: 0,
+    };
+}
+
+// token which contains at least one match, together with spans of all matches in it
+pub struct Occurence {
+    pub token:      Token,
+    pub matches:    Vec<Match>,
+    // edit distance of every match, empty unless matching is approximate
+    pub distances:  Vec<usize>,
+}
+
+impl Occurence {
+    // an occurence always has a match, even if its span isn't known (like a line of a file query)
+    pub fn matches_count(&self) -> usize {
+        return self.matches.len().max(1);
+    }
+
+    pub fn best_distance(&self) -> Option<usize> {
+        return self.distances.iter().min().copied();
+    }
+}
+
+// replaces all non-overlapping matches in `haystack`, returns the result and number of replacements
+pub fn replace_all<M: Matcher + ?Sized>(matcher: &M, haystack: &[u8], replacement: &[u8]) -> (Vec<u8>, usize) {
+    let mut replaced = Vec::<u8>::with_capacity(haystack.len());
+    let mut last_end: usize = 0;
+    let mut count: usize = 0;
+
+    for found in find_iter(matcher, haystack) {
+        replaced.extend_from_slice(&haystack[last_end..found.start]);
+        matcher.expand_match(haystack, found, replacement, &mut replaced);
+        last_end = found.end;
+        count += 1;
+    }
+    replaced.extend_from_slice(&haystack[last_end..]);
+
+    return (replaced, count);
+}
+
+pub fn build_matcher(user_input: &UserInput) -> Box<dyn Matcher> {
+    match try_build_matcher(user_input) {
+        Ok(matcher) => return matcher,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+// same as `build_matcher`, for callers which can't exit on a bad pattern (interactive mode);
+// the error is ready to be shown to the user
+pub fn try_build_matcher(user_input: &UserInput) -> Result<Box<dyn Matcher>, String> {
+    if user_input.fuzzy.is_some() && user_input.has_opt_flag(OptFlag::Regex) {
+        return Err("Approximate matching (--fuzzy) can't be used with regular expressions (-E)".to_string());
+    }
+
+    if let Some(query_text) = &user_input.query {
+        return build_query_matcher(user_input, query_text);
+    }
+
+    let mut matchers = Vec::<Box<dyn Matcher>>::with_capacity(user_input.search_patterns.len());
+    for pattern in user_input.search_patterns.iter() {
+        matchers.push(build_pattern_matcher(user_input, pattern)?);
+    }
+
+    if matchers.len() == 1 {
+        return Ok(matchers.pop().unwrap());
+    }
+
+    return Ok(Box::new(MultiMatcher::new(matchers)));
+}
+
+// every pattern of the query is matched with the same options as -s patterns
+fn build_query_matcher(user_input: &UserInput, query_text: &str) -> Result<Box<dyn Matcher>, String> {
+    let query = parse_query(query_text).map_err(|err| format!("Invalid query: {}\nError: {}", query_text, err))?;
+
+    let mut terms = Vec::<Box<dyn Matcher>>::with_capacity(query.patterns.len());
+    for pattern in query.patterns.iter() {
+        terms.push(build_pattern_matcher(user_input, pattern)?);
+    }
+    let mut shown = Vec::<Box<dyn Matcher>>::with_capacity(query.shown.len());
+    for ind in query.shown.iter() {
+        shown.push(build_pattern_matcher(user_input, &query.patterns[*ind])?);
+    }
+
+    return Ok(Box::new(QueryMatcher::new(query, terms, shown, user_input.query_scope)));
+}
+
+fn build_pattern_matcher(user_input: &UserInput, pattern: &str) -> Result<Box<dyn Matcher>, String> {
+    let ignore_case = user_input.has_opt_flag(OptFlag::IgnoreCase);
+    let whole_word = user_input.has_opt_flag(OptFlag::WholeWord);
+    let whole_line = user_input.has_opt_flag(OptFlag::WholeLine);
+    let multiline = user_input.has_opt_flag(OptFlag::Multiline);
+
+    if user_input.has_opt_flag(OptFlag::Regex) {
+        // boundaries are a part of the expression itself, so the regex engine
+        // can pick an alternative which satisfies them
+        return match RegexMatcher::new(pattern, ignore_case, whole_word, whole_line, multiline) {
+            Ok(m) => Ok(Box::new(m)),
+            Err(err) => Err(format!("Invalid regular expression: {}\nError: {}", pattern, err)),
+        };
+    }
+
+    let mut matcher: Box<dyn Matcher> = if let Some(max_distance) = user_input.fuzzy {
+        match FuzzyMatcher::new(pattern, max_distance, ignore_case) {
+            Ok(m) => Box::new(m),
+            Err(err) => return Err(format!("Pattern can't be matched approximately: {}\nError: {}", pattern, err)),
+        }
+    } else if ignore_case {
+        Box::new(CaseInsensitiveMatcher::new(pattern))
+    } else {
+        Box::new(LiteralMatcher::new(pattern))
+    };
+
+    // boundaries are checked per pattern, so a rejected match of one pattern
+    // doesn't hide a valid match of another one starting at the same position
+    if whole_word {
+        matcher = Box::new(WordMatcher::new(matcher));
+    }
+    if whole_line {
+        matcher = Box::new(LineMatcher::new(matcher));
+    }
+
+    return Ok(matcher);
+}
+
+// exact byte-by-byte matching of a pattern
+pub struct LiteralMatcher {
+    finder: memmem::Finder<'static>,
+}
+
+impl LiteralMatcher {
+    pub fn new(pattern: &str) -> Self {
+        Self {
+            finder: memmem::Finder::new(pattern.as_bytes()).into_owned(),
+        }
+    }
+}
+
+impl Matcher for LiteralMatcher {
+    fn find_at(&self, haystack: &[u8], start: usize) -> Option<Match> {
+        if start > haystack.len() {
+            return None;
+        }
+
+        let ind = self.finder.find(&haystack[start..])?;
+        return Some(Match {
+            start: start + ind,
+            end: start + ind + self.finder.needle().len(),
+        });
+    }
+}
+
+// matching which ignores case of both pattern and haystack, with the same folding as the regex -i;
+// ascii patterns are compared byte-wise, other codepoints via their lowercase and uppercase forms
+pub struct CaseInsensitiveMatcher {
+    pattern:        Vec<u8>,
+    pattern_chars:  Vec<char>,
+    is_ascii:       bool,
+    // 'k' and 's' also match non-ascii KELVIN SIGN and LONG S, so with them
+    // only ascii haystacks can be compared byte-wise
+    ascii_folds:    bool,
+}
+
+impl CaseInsensitiveMatcher {
+    pub fn new(pattern: &str) -> Self {
+        Self {
+            pattern:        pattern.as_bytes().to_vec(),
+            pattern_chars:  pattern.chars().collect(),
+            is_ascii:       pattern.is_ascii(),
+            ascii_folds:    !pattern.bytes().any(|byte| matches!(byte, b'k' | b'K' | b's' | b'S')),
+        }
+    }
+
+    // length in bytes of the haystack part matching the pattern at `pos`, if any
+    fn match_len_at(&self, haystack: &[u8], pos: usize) -> Option<usize> {
+        let mut curr = pos;
+
+        for pattern_char in self.pattern_chars.iter() {
+            let (haystack_char, width) = decode_utf8_char(&haystack[curr..])?;
+            if !chars_eq_ignore_case(haystack_char, *pattern_char) {
+                return None;
             }
+            curr += width;
         }
+
+        return Some(curr - pos);
     }
 
-    return occurences;
+    // candidates are found by the first byte in both cases, then compared as a whole
+    fn find_ascii_at(&self, haystack: &[u8], start: usize) -> Option<Match> {
+        let pattern_len = self.pattern.len();
+        let first_byte = match self.pattern.first() {
+            Some(byte) => *byte,
+            None => return Some(Match { start, end: start }),
+        };
+
+        let mut pos = start;
+        while let Some(ind) = memchr2(first_byte.to_ascii_lowercase(), first_byte.to_ascii_uppercase(), &haystack[pos..]) {
+            let candidate = pos + ind;
+            if haystack.len() - candidate < pattern_len {
+                return None;
+            }
+            if haystack[candidate..candidate + pattern_len].eq_ignore_ascii_case(&self.pattern) {
+                return Some(Match {
+                    start: candidate,
+                    end: candidate + pattern_len,
+                });
+            }
+            pos = candidate + 1;
+        }
+
+        return None;
+    }
 }
 
-fn match_str(search: &String, pattern: &String, ignore_case: bool) -> bool {
-    let search_as_bytes = search.as_bytes();
-    let pattern_as_bytes = pattern.as_bytes();
-    return match_vecs::<u8>(search_as_bytes, pattern_as_bytes, ignore_case, UP_TO_LOW_CASE_OFFSET_ASCII)
+impl Matcher for CaseInsensitiveMatcher {
+    fn find_at(&self, haystack: &[u8], start: usize) -> Option<Match> {
+        if start > haystack.len() {
+            return None;
+        }
+
+        if self.is_ascii && (self.ascii_folds || haystack[start..].is_ascii()) {
+            return self.find_ascii_at(haystack, start);
+        }
+
+        let mut pos = start;
+        while pos <= haystack.len() {
+            if let Some(len) = self.match_len_at(haystack, pos) {
+                return Some(Match {
+                    start: pos,
+                    end: pos + len,
+                });
+            }
+            pos += utf8_char_width(haystack, pos).max(1);
+        }
+
+        return None;
+    }
 }
 
-fn match_vecs<T>(search: &[T], pattern: &[T], ignore_case: bool, up_to_low_offset: T) -> bool
-    where T: Copy + PartialEq + Sub + PartialOrd, <T as Sub>::Output: PartialEq<T>
-{
-    let search_len = search.len();
-    let pattern_len = pattern.len();
-    let mut search_ind: usize = 0;
-    let mut pattern_ind: usize = 0;
+pub struct RegexMatcher {
+    regex: Regex,
+}
 
-    while search_ind < search_len {
-        if cmp_chars::<T>(search[search_ind] as T, pattern[pattern_ind] as T,
-            ignore_case, up_to_low_offset) {
+impl RegexMatcher {
+    // with `multiline` the haystack is a whole file, so ^ and $ match at every line
+    pub fn new(pattern: &str, ignore_case: bool, whole_word: bool, whole_line: bool, multiline: bool) -> Result<Self, regex::Error> {
+        let mut pattern = pattern.to_string();
+        if whole_word {
+            // same rule as for WordMatcher: no word chars right before and after the match
+            pattern = format!(r"\b{{start-half}}(?:{})\b{{end-half}}", pattern);
+        }
+        if whole_line {
+            pattern = format!(r"(?m:^)(?:{})(?m:$)", pattern);
+        }
 
-            let mut curr_search_ind = search_ind + 1;
-            pattern_ind += 1;
+        let regex = RegexBuilder::new(&pattern)
+            .case_insensitive(ignore_case)
+            .multi_line(multiline)
+            .crlf(whole_line || multiline)
+            .build()?;
 
-            while   curr_search_ind < search_len &&
-                    pattern_ind < pattern_len &&
-                    cmp_chars::<T>(search[curr_search_ind] as T, pattern[pattern_ind] as T,
-                    ignore_case, up_to_low_offset)
-            {
+        return Ok(Self { regex });
+    }
+}
 
-                curr_search_ind += 1;
-                pattern_ind += 1;
+impl Matcher for RegexMatcher {
+    fn find_at(&self, haystack: &[u8], start: usize) -> Option<Match> {
+        if start > haystack.len() {
+            return None;
+        }
 
-                // end of 'search' reached
-                if curr_search_ind >= search_len {
-                    if pattern_ind == pattern_len {
-                        return true;
-                    }
-                    return false;
+        // haystacks are utf-8 text, but byte classes like (?-u:\xd0) may match a part of a char;
+        // such spans are widened to whole chars, so text can always be sliced by matches
+        let found = self.regex.find_at(haystack, start)?;
+        return Some(Match {
+            start: floor_char_boundary(haystack, found.start()),
+            end: ceil_char_boundary(haystack, found.end()),
+        });
+    }
+
+    // supports $1, ${name} and $$ in the replacement, same as regex::Captures::expand;
+    // `found` may be widened, so the regex match is looked up within its first char
+    fn expand_match(&self, haystack: &[u8], found: Match, replacement: &[u8], dst: &mut Vec<u8>) {
+        let mut pos = found.start;
+        while pos <= haystack.len() {
+            let caps = match self.regex.captures_at(haystack, pos) {
+                Some(caps) => caps,
+                None => break,
+            };
+            let whole = caps.get(0).unwrap();
+            let whole_start = floor_char_boundary(haystack, whole.start());
+            if whole_start > found.start {
+                break;
+            }
+            if whole_start == found.start && ceil_char_boundary(haystack, whole.end()) == found.end {
+                caps.expand(replacement, dst);
+                return;
+            }
+            pos = whole.start() + 1;
+        }
+        dst.extend_from_slice(replacement);
+    }
+}
+
+// several patterns at once, reports the leftmost match of any of them
+// (the longest one, if a few patterns start at the same position)
+pub struct MultiMatcher {
+    matchers: Vec<Box<dyn Matcher>>,
+}
+
+impl MultiMatcher {
+    pub fn new(matchers: Vec<Box<dyn Matcher>>) -> Self {
+        Self { matchers }
+    }
+}
+
+impl Matcher for MultiMatcher {
+    fn find_at(&self, haystack: &[u8], start: usize) -> Option<Match> {
+        let mut best: Option<Match> = None;
+
+        for matcher in self.matchers.iter() {
+            if let Some(found) = matcher.find_at(haystack, start) {
+                best = match best {
+                    Some(b) if b.start < found.start => Some(b),
+                    Some(b) if b.start == found.start && b.end >= found.end => Some(b),
+                    _ => Some(found),
+                };
+            }
+        }
+
+        return best;
+    }
+
+    fn expand_match(&self, haystack: &[u8], found: Match, replacement: &[u8], dst: &mut Vec<u8>) {
+        // expansion is done by the pattern which produced the match
+        for matcher in self.matchers.iter() {
+            if matcher.find_at(haystack, found.start) == Some(found) {
+                matcher.expand_match(haystack, found, replacement, dst);
+                return;
+            }
+        }
+
+        dst.extend_from_slice(replacement);
+    }
+
+    fn match_distance(&self, haystack: &[u8], found: Match) -> Option<usize> {
+        for matcher in self.matchers.iter() {
+            if matcher.find_at(haystack, found.start) == Some(found) {
+                return matcher.match_distance(haystack, found);
+            }
+        }
+
+        return None;
+    }
+}
+
+// longest pattern in chars which fits into bit vectors of the fuzzy search
+const FUZZY_MAX_PATTERN_LEN: usize = 64;
+
+// approximate matching: finds substrings within `max_distance` edits (insertions, deletions, substitutions)
+// of the pattern, using the bit-parallel algorithm by Myers which handles a char in a few word operations
+pub struct FuzzyMatcher {
+    pattern_chars:  Vec<char>,
+    // bit i of a mask is set if the pattern has the char at position i
+    ascii_masks:    [u64; 128],
+    other_masks:    Vec<(char, u64)>,
+    max_distance:   usize,
+    ignore_case:    bool,
+}
+
+impl FuzzyMatcher {
+    pub fn new(pattern: &str, max_distance: usize, ignore_case: bool) -> Result<Self, String> {
+        let pattern_chars: Vec<char> = pattern.chars().map(|c| fold_case(c, ignore_case)).collect();
+        if pattern_chars.len() > FUZZY_MAX_PATTERN_LEN {
+            return Err(format!("pattern is longer than {} chars", FUZZY_MAX_PATTERN_LEN));
+        }
+        if max_distance >= pattern_chars.len() {
+            return Err(String::from("distance has to be less than length of the pattern, otherwise anything matches"));
+        }
+
+        let mut ascii_masks = [0u64; 128];
+        let mut other_masks = Vec::<(char, u64)>::new();
+        for (ind, c) in pattern_chars.iter().enumerate() {
+            if c.is_ascii() {
+                ascii_masks[*c as usize] |= 1 << ind;
+                continue;
+            }
+            match other_masks.iter_mut().find(|(other, _)| other == c) {
+                Some((_, mask)) => *mask |= 1 << ind,
+                None => other_masks.push((*c, 1 << ind)),
+            }
+        }
+
+        return Ok(Self {
+            pattern_chars,
+            ascii_masks,
+            other_masks,
+            max_distance,
+            ignore_case,
+        });
+    }
+
+    fn char_mask(&self, c: char) -> u64 {
+        if c.is_ascii() {
+            return self.ascii_masks[c as usize];
+        }
+        return self.other_masks.iter()
+            .find(|(other, _)| *other == c)
+            .map(|(_, mask)| *mask)
+            .unwrap_or(0);
+    }
+
+    // char at `pos` folded for comparison, with its width in bytes; invalid bytes never match
+    fn char_at(&self, haystack: &[u8], pos: usize) -> (Option<char>, usize) {
+        let byte = haystack[pos];
+        if byte.is_ascii() {
+            return (Some(fold_case(byte as char, self.ignore_case)), 1);
+        }
+        match decode_utf8_char(&haystack[pos..]) {
+            Some((c, width)) => return (Some(fold_case(c, self.ignore_case)), width),
+            None => return (None, 1),
+        }
+    }
+
+    // chars of haystack[from..to] folded for comparison
+    fn chars_between(&self, haystack: &[u8], from: usize, to: usize) -> Vec<(Option<char>, usize)> {
+        let mut chars = Vec::<(Option<char>, usize)>::new();
+        let mut pos = from;
+        while pos < to {
+            let (c, width) = self.char_at(haystack, pos);
+            chars.push((c, pos));
+            pos += width;
+        }
+        return chars;
+    }
+
+    // start of the closest match ending at `end`, among starts not before `start`;
+    // the pattern is aligned backwards from `end`, shorter matches win ties
+    fn best_start(&self, haystack: &[u8], start: usize, end: usize) -> (usize, usize) {
+        let pattern_len = self.pattern_chars.len();
+
+        let window_start = {
+            let mut pos = end;
+            let mut chars_back: usize = 0;
+            while pos > start && chars_back < pattern_len + self.max_distance {
+                pos -= 1;
+                while pos > start && (haystack[pos] & 0xC0) == 0x80 {
+                    pos -= 1;
                 }
+                chars_back += 1;
             }
+            pos
+        };
+        let window = self.chars_between(haystack, window_start, end);
+
+        // distances between reversed pattern prefixes and the reversed text taken so far
+        let mut column: Vec<usize> = (0..=pattern_len).collect();
+        let mut best = (end, column[pattern_len]);
 
-            // we found a match
-            if pattern_ind == pattern_len {
-                return true;
+        for (taken, (c, char_start)) in window.iter().rev().enumerate() {
+            let mut diagonal = column[0];
+            column[0] = taken + 1;
+            for i in 1..=pattern_len {
+                let pattern_char = self.pattern_chars[pattern_len - i];
+                let substitution = diagonal + if Some(pattern_char) == *c { 0 } else { 1 };
+                diagonal = column[i];
+                column[i] = substitution.min(column[i] + 1).min(column[i - 1] + 1);
             }
-            else {
-                pattern_ind = 0;
-                search_ind += 1;
+
+            if column[pattern_len] < best.1 {
+                best = (*char_start, column[pattern_len]);
             }
         }
-        else {
-            search_ind += 1;
+
+        return best;
+    }
+}
+
+impl Matcher for FuzzyMatcher {
+    fn find_at(&self, haystack: &[u8], start: usize) -> Option<Match> {
+        if start > haystack.len() {
+            return None;
+        }
+
+        let last_bit: u64 = 1 << (self.pattern_chars.len() - 1);
+        // vertical positive and negative deltas of the distance matrix column
+        let mut positive: u64 = !0;
+        let mut negative: u64 = 0;
+        let mut distance = self.pattern_chars.len();
+        // the closest end found so far, once the distance went under the limit
+        let mut found_end: Option<(usize, usize)> = None;
+        // after the first hit, the rest of the pattern may still be matched in up to `max_distance` chars
+        let mut lookahead_left = self.max_distance;
+
+        let mut pos = start;
+        while pos < haystack.len() {
+            let (c, width) = self.char_at(haystack, pos);
+            let eq = c.map(|c| self.char_mask(c)).unwrap_or(0);
+
+            let xv = eq | negative;
+            let xh = ((eq & positive).wrapping_add(positive) ^ positive) | eq;
+            let mut horizontal_positive = negative | !(xh | positive);
+            let mut horizontal_negative = positive & xh;
+
+            if horizontal_positive & last_bit != 0 {
+                distance += 1;
+            } else if horizontal_negative & last_bit != 0 {
+                distance -= 1;
+            }
+
+            // a match may start anywhere, so the first row stays zero
+            horizontal_positive <<= 1;
+            horizontal_negative <<= 1;
+            positive = horizontal_negative | !(xv | horizontal_positive);
+            negative = horizontal_positive & xv;
+            pos += width;
+
+            // after the first hit the match is extended to the closest end nearby, longer one on ties
+            match found_end {
+                Some((_, best_distance)) => {
+                    if distance <= best_distance {
+                        found_end = Some((pos, distance));
+                    }
+                    if lookahead_left == 0 {
+                        break;
+                    }
+                    lookahead_left -= 1;
+                },
+                None if distance <= self.max_distance => found_end = Some((pos, distance)),
+                None => (),
+            }
         }
+
+        let (end, _) = found_end?;
+        let (match_start, _) = self.best_start(haystack, start, end);
+        return Some(Match {
+            start: match_start,
+            end,
+        });
     }
 
-    return false;
+    fn match_distance(&self, haystack: &[u8], found: Match) -> Option<usize> {
+        let (_, distance) = self.best_start(haystack, found.start, found.end);
+        return Some(distance);
+    }
 }
 
-fn cmp_chars<T>(c1: T, c2: T, ignore_case: bool, up_to_low_offset: T) -> bool
-    where T: PartialEq + Sub + PartialOrd, <T as Sub>::Output: PartialEq<T>
-{
-    if ignore_case {
-        if c1 == c2 { return true } else {
-            if c1 > c2 {
-                return c1 - c2 == up_to_low_offset
-            } else {
-                return c2 - c1 == up_to_low_offset
+// accepts only matches of the inner matcher which are separate words:
+// chars around the match must not be word characters (unicode letters, digits or '_')
+pub struct WordMatcher<M: Matcher> {
+    inner: M,
+}
+
+impl<M: Matcher> WordMatcher<M> {
+    pub fn new(inner: M) -> Self {
+        Self { inner }
+    }
+}
+
+impl<M: Matcher> Matcher for WordMatcher<M> {
+    fn find_at(&self, haystack: &[u8], start: usize) -> Option<Match> {
+        let mut pos = start;
+
+        while let Some(found) = self.inner.find_at(haystack, pos) {
+            let word_before = match decode_utf8_char_before(haystack, found.start) {
+                Some(c) => is_word_char(c),
+                None => false,
+            };
+            let word_after = match decode_utf8_char(&haystack[found.end..]) {
+                Some((c, _)) => is_word_char(c),
+                None => false,
+            };
+
+            if !word_before && !word_after {
+                return Some(found);
             }
+            pos = found.start + utf8_char_width(haystack, found.start).max(1);
         }
-    } else {
-        return c1 == c2
+
+        return None;
+    }
+
+    fn expand_match(&self, haystack: &[u8], found: Match, replacement: &[u8], dst: &mut Vec<u8>) {
+        self.inner.expand_match(haystack, found, replacement, dst);
+    }
+
+    fn match_distance(&self, haystack: &[u8], found: Match) -> Option<usize> {
+        return self.inner.match_distance(haystack, found);
+    }
+}
+
+// accepts only matches of the inner matcher which cover a whole line,
+// line terminators ("\n" or "\r\n") are not part of the line
+pub struct LineMatcher<M: Matcher> {
+    inner: M,
+}
+
+impl<M: Matcher> LineMatcher<M> {
+    pub fn new(inner: M) -> Self {
+        Self { inner }
+    }
+}
+
+impl<M: Matcher> Matcher for LineMatcher<M> {
+    fn find_at(&self, haystack: &[u8], start: usize) -> Option<Match> {
+        if start > haystack.len() {
+            return None;
+        }
+
+        let mut line_start = match haystack[..start].iter().rposition(|b| *b == b'\n') {
+            Some(ind) => ind + 1,
+            None => 0,
+        };
+
+        while line_start <= haystack.len() {
+            let next_line_start = match haystack[line_start..].iter().position(|b| *b == b'\n') {
+                Some(ind) => line_start + ind + 1,
+                None => haystack.len() + 1,
+            };
+            let mut line_end = next_line_start - 1;
+            if line_end > line_start && haystack[line_end - 1] == b'\r' {
+                line_end -= 1;
+            }
+
+            // a match which covers the line has to start at the line start
+            if line_start >= start {
+                if let Some(found) = self.inner.find_at(&haystack[..line_end], line_start) {
+                    if found.start == line_start && found.end == line_end {
+                        return Some(found);
+                    }
+                }
+            }
+
+            line_start = next_line_start;
+        }
+
+        return None;
+    }
+
+    fn expand_match(&self, haystack: &[u8], found: Match, replacement: &[u8], dst: &mut Vec<u8>) {
+        self.inner.expand_match(haystack, found, replacement, dst);
+    }
+
+    fn match_distance(&self, haystack: &[u8], found: Match) -> Option<usize> {
+        return self.inner.match_distance(haystack, found);
+    }
+}
+
+fn is_word_char(c: char) -> bool {
+    return c.is_alphanumeric() || c == '_';
+}
+
+// simple case folding to a single lowercase char, enough to compare chars one by one
+fn fold_case(c: char, ignore_case: bool) -> char {
+    if !ignore_case {
+        return c;
+    }
+    return c.to_lowercase().next().unwrap_or(c);
+}
+
+// close to simple case folding of the regex engine: chars are equal if their lowercase or uppercase
+// forms are, which also covers σ and ς or ſ and s; Turkish dotless ı folds only into itself
+fn chars_eq_ignore_case(c1: char, c2: char) -> bool {
+    if c1 == c2 {
+        return true;
+    }
+    if c1 == '\u{131}' || c2 == '\u{131}' {
+        return false;
+    }
+    return c1.to_lowercase().eq(c2.to_lowercase()) || c1.to_uppercase().eq(c2.to_uppercase());
+}
+
+// decodes the first utf-8 codepoint of `bytes`, returns it with its width in bytes
+pub fn decode_utf8_char(bytes: &[u8]) -> Option<(char, usize)> {
+    let width = utf8_char_width(bytes, 0);
+    if width == 0 || width > bytes.len() {
+        return None;
+    }
+
+    let c = std::str::from_utf8(&bytes[..width]).ok()?.chars().next()?;
+    return Some((c, width));
+}
+
+// decodes the utf-8 codepoint which ends right before `pos`
+fn decode_utf8_char_before(bytes: &[u8], pos: usize) -> Option<char> {
+    if pos == 0 || pos > bytes.len() {
+        return None;
+    }
+
+    // utf-8 codepoint is at most 4 bytes long, skip back over continuation bytes
+    let mut char_start = pos - 1;
+    while char_start > 0 && pos - char_start < 4 && (bytes[char_start] & 0xC0) == 0x80 {
+        char_start -= 1;
+    }
+
+    let (c, width) = decode_utf8_char(&bytes[char_start..pos])?;
+    if char_start + width != pos {
+        return None;
+    }
+
+    return Some(c);
+}
+
+fn is_continuation_byte(byte: u8) -> bool {
+    return (byte & 0xC0) == 0x80;
+}
+
+// closest start of a utf-8 codepoint at or before `pos`
+fn floor_char_boundary(bytes: &[u8], pos: usize) -> usize {
+    let mut pos = pos.min(bytes.len());
+    while pos > 0 && pos < bytes.len() && is_continuation_byte(bytes[pos]) {
+        pos -= 1;
+    }
+    return pos;
+}
+
+// closest start of a utf-8 codepoint (or the end) at or after `pos`
+fn ceil_char_boundary(bytes: &[u8], pos: usize) -> usize {
+    let mut pos = pos.min(bytes.len());
+    while pos < bytes.len() && is_continuation_byte(bytes[pos]) {
+        pos += 1;
+    }
+    return pos;
+}
+
+// width of utf-8 codepoint starting at `pos`, 0 if `pos` is out of bounds
+pub fn utf8_char_width(bytes: &[u8], pos: usize) -> usize {
+    if pos >= bytes.len() {
+        return 0;
+    }
+
+    return match bytes[pos] {
+        0x00..=0x7F => 1,
+        0xC0..=0xDF => 2,
+        0xE0..=0xEF => 3,
+        0xF0..=0xF7 => 4,
+        // continuation or invalid byte
+        _ => 1,
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // every match of the fuzzy pattern with its distance
+    fn fuzzy_matches(pattern: &str, max_distance: usize, ignore_case: bool, haystack: &str) -> Vec<(String, usize)> {
+        let matcher = FuzzyMatcher::new(pattern, max_distance, ignore_case).unwrap();
+        let bytes = haystack.as_bytes();
+        let mut matches = Vec::<(String, usize)>::new();
+        let mut pos = 0;
+        while let Some(found) = matcher.find_at(bytes, pos) {
+            let distance = matcher.match_distance(bytes, found).unwrap();
+            matches.push((haystack[found.start..found.end].to_string(), distance));
+            pos = found.end.max(pos + 1);
+        }
+        return matches;
+    }
+
+    fn found(text: &str, distance: usize) -> (String, usize) {
+        return (text.to_string(), distance);
+    }
+
+    #[test]
+    fn fuzzy_exact() {
+        assert_eq!(fuzzy_matches("hello", 0, false, "say hello world"), vec![found("hello", 0)]);
+        assert_eq!(fuzzy_matches("hello", 0, false, "say helo world"), vec![]);
+        assert_eq!(fuzzy_matches("hello", 0, false, "hello hello"), vec![found("hello", 0), found("hello", 0)]);
+    }
+
+    #[test]
+    fn fuzzy_one_edit() {
+        // deletion, substitution and insertion
+        assert_eq!(fuzzy_matches("hello", 1, false, "say helo world"), vec![found("helo", 1)]);
+        assert_eq!(fuzzy_matches("hello", 1, false, "say hallo world"), vec![found("hallo", 1)]);
+        assert_eq!(fuzzy_matches("hello", 1, false, "say helllo world"), vec![found("helllo", 1)]);
+        assert_eq!(fuzzy_matches("hello", 1, false, "say hxllx world"), vec![]);
+        assert_eq!(fuzzy_matches("hello", 1, false, "helo hallo"), vec![found("helo", 1), found("hallo", 1)]);
+    }
+
+    #[test]
+    fn fuzzy_two_edits() {
+        assert_eq!(fuzzy_matches("hello", 2, false, "say hxllx there"), vec![found("hxllx", 2)]);
+        assert_eq!(fuzzy_matches("hello", 2, false, "say hll"), vec![found("hll", 2)]);
+        // a substituted last char costs the same as the missing 'o', the longer end wins
+        assert_eq!(fuzzy_matches("hello", 2, false, "say hll there"), vec![found("hll ", 2)]);
+        assert_eq!(fuzzy_matches("hello", 2, false, "say hxyzo there"), vec![]);
+    }
+
+    #[test]
+    fn fuzzy_non_ascii() {
+        assert_eq!(fuzzy_matches("привет", 1, false, "скажи превет миру"), vec![found("превет", 1)]);
+        assert_eq!(fuzzy_matches("привет", 1, false, "скажи привт миру"), vec![found("привт", 1)]);
+        assert_eq!(fuzzy_matches("日本語", 1, false, "これは日本誤です"), vec![found("日本誤", 1)]);
+    }
+
+    #[test]
+    fn fuzzy_ignore_case() {
+        assert_eq!(fuzzy_matches("Hello", 0, true, "say HELLO"), vec![found("HELLO", 0)]);
+        assert_eq!(fuzzy_matches("Hello", 0, false, "say HELLO"), vec![]);
+        assert_eq!(fuzzy_matches("ПРИВЕТ", 1, true, "скажи превет"), vec![found("превет", 1)]);
+    }
+
+    #[test]
+    fn fuzzy_match_bounds() {
+        // the first hit ("abc", one deletion) is extended to the closer match right after it
+        assert_eq!(fuzzy_matches("abcd", 1, false, "xabcdx"), vec![found("abcd", 0)]);
+        // the extension stops once the distance grows
+        assert_eq!(fuzzy_matches("abcd", 1, false, "xabcdyyy"), vec![found("abcd", 0)]);
+        // on equal distance the shorter match wins at the start ("bc" instead of "xbc")
+        assert_eq!(fuzzy_matches("abc", 1, false, "xbc"), vec![found("bc", 1)]);
+        // and the longer one at the end, so a substituted last char is kept
+        assert_eq!(fuzzy_matches("abc", 1, false, "abx"), vec![found("abx", 1)]);
+    }
+
+    // spans of all matches found one after another
+    fn match_spans(matcher: &dyn Matcher, haystack: &str) -> Vec<(usize, usize)> {
+        let bytes = haystack.as_bytes();
+        let mut spans = Vec::<(usize, usize)>::new();
+        let mut pos = 0;
+        while let Some(found) = matcher.find_at(bytes, pos) {
+            spans.push((found.start, found.end));
+            pos = found.end.max(pos + 1);
+        }
+        return spans;
+    }
+
+    #[test]
+    fn word_matcher_accepts_separate_words() {
+        let haystack = "cat concat cat_ cats (cat)\ncat";
+        let expected = vec![(0, 3), (22, 25), (27, 30)];
+        assert_eq!(match_spans(&WordMatcher::new(LiteralMatcher::new("cat")), haystack), expected);
+        assert_eq!(match_spans(&RegexMatcher::new("cat", false, true, false, false).unwrap(), haystack), expected);
+    }
+
+    #[test]
+    fn word_matcher_treats_unicode_letters_as_word_chars() {
+        // "é" and "д" are word chars, "-" and "«" are not
+        let haystack = "éa aд a-a «a»";
+        let expected = vec![(8, 9), (10, 11), (14, 15)];
+        assert_eq!(match_spans(&WordMatcher::new(LiteralMatcher::new("a")), haystack), expected);
+        assert_eq!(match_spans(&RegexMatcher::new("a", false, true, false, false).unwrap(), haystack), expected);
+    }
+
+    #[test]
+    fn line_matcher_accepts_whole_lines() {
+        let haystack = "abc\r\nxabc\nabc \nabc\r\n\nabc";
+        let expected = vec![(0, 3), (15, 18), (21, 24)];
+        assert_eq!(match_spans(&LineMatcher::new(LiteralMatcher::new("abc")), haystack), expected);
+        assert_eq!(match_spans(&RegexMatcher::new("abc", false, false, true, false).unwrap(), haystack), expected);
+    }
+
+    #[test]
+    fn line_matcher_excludes_crlf_from_the_line() {
+        let matcher = RegexMatcher::new("a.*", false, false, true, false).unwrap();
+        assert_eq!(match_spans(&matcher, "ab\r\nac\r\n"), vec![(0, 2), (4, 6)]);
+        let matcher = LineMatcher::new(LiteralMatcher::new("ab"));
+        assert_eq!(match_spans(&matcher, "ab\r\nab\r\r\nab"), vec![(0, 2), (9, 11)]);
+    }
+
+    #[test]
+    fn regex_replace_expands_groups_in_non_ascii_lines() {
+        let matcher = RegexMatcher::new(r"(\w+)-(\w+)", false, false, false, false).unwrap();
+        let (replaced, count) = replace_all(&matcher, "привет-мир, ä-ö".as_bytes(), b"$2-$1");
+        assert_eq!((String::from_utf8(replaced).unwrap(), count), ("мир-привет, ö-ä".to_string(), 2));
+
+        // the match starts inside "п" and is widened to the whole char, groups stay the same
+        let matcher = RegexMatcher::new(r"(?-u:\xbf)(x)", false, false, false, false).unwrap();
+        let (replaced, count) = replace_all(&matcher, "aпx ".as_bytes(), b"<$1>");
+        assert_eq!((String::from_utf8(replaced).unwrap(), count), ("a<x> ".to_string(), 1));
+    }
+
+    #[test]
+    fn case_insensitive_folds_like_regex() {
+        let cases = [
+            ("k", "\u{212A}"), ("kelvin", "5 \u{212A}ELVIN"), ("s", "\u{17F}"), ("σ", "ς"), ("ß", "ẞ"),
+            ("i", "\u{131}"), ("i", "\u{130}"), ("I", "\u{131}"), ("привет", "ПРИВЕТ"), ("abc", "xAbC"), ("abc", "ab"),
+        ];
+        for (pattern, haystack) in cases {
+            let literal = CaseInsensitiveMatcher::new(pattern).find_at(haystack.as_bytes(), 0);
+            let regex = RegexMatcher::new(pattern, true, false, false, false).unwrap().find_at(haystack.as_bytes(), 0);
+            assert_eq!(literal, regex, "{} in {}", pattern, haystack);
+        }
+    }
+
+    #[test]
+    fn fuzzy_rejects_bad_arguments() {
+        assert!(FuzzyMatcher::new("ab", 2, false).is_err());
+        assert!(FuzzyMatcher::new(&"a".repeat(FUZZY_MAX_PATTERN_LEN + 1), 1, false).is_err());
+        assert!(FuzzyMatcher::new(&"a".repeat(FUZZY_MAX_PATTERN_LEN), 1, false).is_ok());
     }
 }
@@ -0,0 +1,528 @@
+use std::io::{Stdout, Write};
+use std::path::Path;
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crossterm::cursor::{Hide, MoveTo, Show};
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::style::{Attribute, Color, Print, ResetColor, SetAttribute, SetForegroundColor};
+use crossterm::terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen};
+use crossterm::{execute, queue};
+
+use crate::cli_input::{OptFlag, UserInput};
+use crate::fs_related::{search_each, FileData, Token};
+use crate::str_pattern_match::{try_build_matcher, Match};
+
+// a search starts once typing pauses for this long, not on every key
+const SEARCH_DELAY: Duration = Duration::from_millis(150);
+// how long a key is waited for before new results are picked up
+const TICK: Duration = Duration::from_millis(50);
+// files with matches taken from a running search per tick, so keys are handled even while results flood in
+const MAX_FILES_PER_TICK: usize = 500;
+// the search is stopped after that many hits, more can't be looked through anyway
+const MAX_HITS: usize = 100_000;
+// bigger files are not read for a preview
+const MAX_PREVIEW_FILE_SIZE: u64 = 64 * 1024 * 1024;
+const PROMPT: &str = "> ";
+const LINE_NUM_WIDTH: usize = 6;
+
+// a single matched line of the list
+struct Hit {
+    file_path:  String,
+    line_num:   usize,
+    line:       String,
+    matches:    Vec<Match>,
+}
+
+enum SearchMsg {
+    Found(FileData),
+    Done,
+    Failed(String),
+}
+
+// search running in its own thread, stopped when dropped
+struct SearchRun {
+    receiver:   Receiver<SearchMsg>,
+    stop:       Arc<AtomicBool>,
+}
+
+impl Drop for SearchRun {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+fn start_search(user_input: UserInput) -> SearchRun {
+    let (sender, receiver) = mpsc::channel::<SearchMsg>();
+    let stop = Arc::new(AtomicBool::new(false));
+    let thread_stop = Arc::clone(&stop);
+
+    std::thread::spawn(move || {
+        let matcher = match try_build_matcher(&user_input) {
+            Ok(matcher) => matcher,
+            Err(err) => {
+                let _ = sender.send(SearchMsg::Failed(err));
+                return;
+            }
+        };
+        // a closed channel means results are not needed anymore
+        search_each(&user_input, &matcher, &thread_stop, &mut |file_d| sender.send(SearchMsg::Found(file_d)).is_ok());
+        let _ = sender.send(SearchMsg::Done);
+    });
+
+    return SearchRun { receiver, stop };
+}
+
+// raw mode and the alternate screen, given back to the shell however the interface exits
+struct TerminalGuard;
+
+impl TerminalGuard {
+    fn enter(stdout: &mut Stdout) -> std::io::Result<Self> {
+        terminal::enable_raw_mode()?;
+        execute!(stdout, EnterAlternateScreen, Hide)?;
+        return Ok(Self);
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        let _ = execute!(std::io::stdout(), Show, LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+// full screen interface: the pattern prompt, the list of hits, and a preview of the selected one
+pub struct Tui {
+    // input of every search, with the edited pattern put in
+    user_input:     UserInput,
+    pattern:        String,
+    // the pattern is a query if the interface was started with --query
+    edits_query:    bool,
+    hits:           Vec<Hit>,
+    files_count:    usize,
+    selected:       usize,
+    // first hit shown in the list
+    scroll:         usize,
+    list_height:    usize,
+    search:         Option<SearchRun>,
+    // the pattern was changed at that moment and its search isn't started yet
+    changed_at:     Option<Instant>,
+    error:          Option<String>,
+    // result of the last action, shown until the next key
+    message:        Option<String>,
+    // path and lines of the previewed file, kept while hits of the same file are selected
+    preview:        Option<(String, Vec<String>)>,
+    dirty:          bool,
+}
+
+impl Tui {
+    pub fn new(user_input: &UserInput) -> Result<Self, String> {
+        if user_input.search_patterns.len() > 1 {
+            return Err("Interactive mode edits a single pattern, use --query to combine a few".to_string());
+        }
+
+        let mut user_input = user_input.clone();
+        let edits_query = user_input.query.is_some();
+        let pattern = match edits_query {
+            true => user_input.query.take().unwrap_or_default(),
+            false => user_input.search_patterns.pop().unwrap_or_default(),
+        };
+
+        // errors of single files would be printed over the interface;
+        // hits are whole lines with match spans, same as for -o, and need numbers to open an editor at
+        for flag in [OptFlag::Quiet, OptFlag::OnlyMatching, OptFlag::LineNumbers] {
+            if !user_input.has_opt_flag(flag.clone()) {
+                user_input.opt_flags.push(flag);
+            }
+        }
+
+        return Ok(Self {
+            user_input,
+            pattern,
+            edits_query,
+            hits:           Vec::new(),
+            files_count:    0,
+            selected:       0,
+            scroll:         0,
+            list_height:    1,
+            search:         None,
+            changed_at:     None,
+            error:          None,
+            message:        None,
+            preview:        None,
+            dirty:          true,
+        });
+    }
+
+    pub fn run(&mut self) -> std::io::Result<()> {
+        let mut stdout = std::io::stdout();
+        let _guard = TerminalGuard::enter(&mut stdout)?;
+        self.restart_search();
+
+        loop {
+            self.receive_results();
+            if self.dirty {
+                self.draw(&mut stdout)?;
+                self.dirty = false;
+            }
+
+            if event::poll(TICK)? {
+                match event::read()? {
+                    Event::Key(key) if key.kind == KeyEventKind::Press => {
+                        if !self.handle_key(key, &mut stdout)? {
+                            return Ok(());
+                        }
+                        self.dirty = true;
+                    },
+                    Event::Resize(_, _) => self.dirty = true,
+                    _ => (),
+                }
+            }
+
+            if self.changed_at.is_some_and(|changed_at| changed_at.elapsed() >= SEARCH_DELAY) {
+                self.restart_search();
+            }
+        }
+    }
+
+    fn restart_search(&mut self) {
+        self.changed_at = None;
+        // dropping the previous search stops it
+        self.search = None;
+        self.hits.clear();
+        self.files_count = 0;
+        self.selected = 0;
+        self.scroll = 0;
+        self.error = None;
+        self.dirty = true;
+
+        // an empty pattern would match every line
+        if self.pattern.is_empty() {
+            return;
+        }
+
+        let mut user_input = self.user_input.clone();
+        if self.edits_query {
+            user_input.query = Some(self.pattern.clone());
+        } else {
+            user_input.search_patterns = vec![self.pattern.clone()];
+        }
+        self.search = Some(start_search(user_input));
+    }
+
+    fn receive_results(&mut self) {
+        let search = match &self.search {
+            Some(search) => search,
+            None => return,
+        };
+
+        let mut finished = false;
+        for _ in 0..MAX_FILES_PER_TICK {
+            match search.receiver.try_recv() {
+                Ok(SearchMsg::Found(file_d)) => {
+                    self.files_count += 1;
+                    for occurence in file_d.occurences {
+                        let (line_num, line) = match occurence.token {
+                            Token::TokenStrLine(token_line) => (token_line.line_num, token_line.contents),
+                            Token::TokenStr(contents) => (0, contents),
+                        };
+                        self.hits.push(Hit {
+                            file_path: file_d.file_path.clone(),
+                            line_num,
+                            line,
+                            matches: occurence.matches,
+                        });
+                    }
+                    self.dirty = true;
+                    if self.hits.len() >= MAX_HITS {
+                        self.message = Some(format!("Search stopped after {} matches", MAX_HITS));
+                        finished = true;
+                        break;
+                    }
+                },
+                Ok(SearchMsg::Failed(err)) => {
+                    self.error = Some(err);
+                    finished = true;
+                    break;
+                },
+                Ok(SearchMsg::Done) | Err(TryRecvError::Disconnected) => {
+                    finished = true;
+                    break;
+                },
+                Err(TryRecvError::Empty) => break,
+            }
+        }
+
+        if finished {
+            self.search = None;
+            self.dirty = true;
+        }
+    }
+
+    // returns false when the interface should be closed
+    fn handle_key(&mut self, key: KeyEvent, stdout: &mut Stdout) -> std::io::Result<bool> {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        self.message = None;
+
+        match key.code {
+            KeyCode::Esc => return Ok(false),
+            KeyCode::Char('c') if ctrl => return Ok(false),
+            KeyCode::Enter => self.open_selected(stdout)?,
+            KeyCode::Up => self.move_selection(-1),
+            KeyCode::Char('p') if ctrl => self.move_selection(-1),
+            KeyCode::Down => self.move_selection(1),
+            KeyCode::Char('n') if ctrl => self.move_selection(1),
+            KeyCode::PageUp => self.move_selection(-(self.list_height as isize)),
+            KeyCode::PageDown => self.move_selection(self.list_height as isize),
+            KeyCode::Home => self.move_selection(isize::MIN),
+            KeyCode::End => self.move_selection(isize::MAX),
+            KeyCode::Backspace => {
+                self.pattern.pop();
+                self.changed_at = Some(Instant::now());
+            },
+            KeyCode::Char('u') if ctrl => {
+                self.pattern.clear();
+                self.changed_at = Some(Instant::now());
+            },
+            KeyCode::Char('w') if ctrl => {
+                let kept_len = self.pattern.trim_end().rfind(' ').map_or(0, |ind| ind + 1);
+                self.pattern.truncate(kept_len);
+                self.changed_at = Some(Instant::now());
+            },
+            KeyCode::Char(c) if !ctrl && !key.modifiers.contains(KeyModifiers::ALT) => {
+                self.pattern.push(c);
+                self.changed_at = Some(Instant::now());
+            },
+            _ => (),
+        }
+
+        return Ok(true);
+    }
+
+    fn move_selection(&mut self, offset: isize) {
+        if self.hits.is_empty() {
+            return;
+        }
+        let last = self.hits.len() - 1;
+        self.selected = self.selected.saturating_add_signed(offset).min(last);
+    }
+
+    // the editor gets the terminal for the time it runs, `$EDITOR +line path` is understood by most of them
+    fn open_selected(&mut self, stdout: &mut Stdout) -> std::io::Result<()> {
+        let hit = match self.hits.get(self.selected) {
+            Some(hit) => hit,
+            None => return Ok(()),
+        };
+        // members of archives don't exist as files
+        if !Path::new(&hit.file_path).is_file() {
+            self.message = Some(format!("{} can't be opened in an editor", hit.file_path));
+            return Ok(());
+        }
+
+        let editor = std::env::var("VISUAL").or_else(|_| std::env::var("EDITOR")).unwrap_or_else(|_| "vi".to_string());
+        let mut editor_parts = editor.split_whitespace();
+        let program = editor_parts.next().unwrap_or("vi");
+
+        execute!(stdout, Show, LeaveAlternateScreen)?;
+        terminal::disable_raw_mode()?;
+
+        let status = Command::new(program)
+            .args(editor_parts)
+            .arg(format!("+{}", hit.line_num + 1))
+            .arg(&hit.file_path)
+            .status();
+
+        terminal::enable_raw_mode()?;
+        execute!(stdout, EnterAlternateScreen, Hide, Clear(ClearType::All))?;
+
+        match status {
+            Ok(status) if !status.success() => self.message = Some(format!("{} exited with {}", program, status)),
+            Ok(_) => (),
+            Err(err) => self.message = Some(format!("Can't run {}: {}", program, err)),
+        }
+        // the file may have been changed in the editor
+        self.preview = None;
+        return Ok(());
+    }
+
+    fn draw(&mut self, stdout: &mut Stdout) -> std::io::Result<()> {
+        let (width, height) = terminal::size()?;
+        let (width, height) = (width as usize, height as usize);
+        // the frame is written at once, so it never shows half drawn
+        let mut out = Vec::<u8>::with_capacity(width * height * 2);
+
+        // list takes the upper half, preview the lower one, below a separator
+        let body_height = height.saturating_sub(2);
+        self.list_height = if body_height >= 8 { body_height / 2 } else { body_height.max(1) };
+        let preview_height = body_height.saturating_sub(self.list_height + 1);
+
+        if self.selected < self.scroll {
+            self.scroll = self.selected;
+        } else if self.selected >= self.scroll + self.list_height {
+            self.scroll = self.selected + 1 - self.list_height;
+        }
+
+        queue!(out, Hide, MoveTo(0, 1), Clear(ClearType::CurrentLine))?;
+        self.draw_status(&mut out, width)?;
+
+        for row in 0..self.list_height {
+            queue!(out, MoveTo(0, (row + 2) as u16), Clear(ClearType::CurrentLine))?;
+            if let Some(hit) = self.hits.get(self.scroll + row) {
+                draw_hit(&mut out, hit, self.scroll + row == self.selected, width)?;
+            }
+        }
+
+        if preview_height > 0 {
+            self.draw_preview(&mut out, self.list_height + 2, preview_height, width)?;
+        }
+
+        // prompt is drawn last, so the cursor stays at its end
+        let prompt = format!("{}{}", PROMPT, self.pattern);
+        let prompt_width = prompt.chars().count().min(width.saturating_sub(1));
+        queue!(out, MoveTo(0, 0), Clear(ClearType::CurrentLine), Print(prompt.chars().take(prompt_width).collect::<String>()))?;
+        queue!(out, MoveTo(prompt_width as u16, 0), Show)?;
+
+        stdout.write_all(&out)?;
+        return stdout.flush();
+    }
+
+    fn draw_status(&self, out: &mut Vec<u8>, width: usize) -> std::io::Result<()> {
+        let (color, status) = if let Some(error) = &self.error {
+            (Color::Red, error.replace('\n', " "))
+        } else if let Some(message) = &self.message {
+            (Color::Yellow, message.clone())
+        } else if self.pattern.is_empty() {
+            (Color::DarkGrey, "Type a pattern; Up/Down select, Enter opens in $EDITOR, Esc quits".to_string())
+        } else {
+            let state = if self.search.is_some() || self.changed_at.is_some() { ", searching..." } else { "" };
+            (Color::DarkGrey, format!("{} matches in {} files{}", self.hits.len(), self.files_count, state))
+        };
+
+        return queue!(out, SetForegroundColor(color), Print(fit_width(&status, width)), ResetColor);
+    }
+
+    fn draw_preview(&mut self, out: &mut Vec<u8>, top: usize, height: usize, width: usize) -> std::io::Result<()> {
+        let hit = match self.hits.get(self.selected) {
+            Some(hit) => hit,
+            None => {
+                for row in top..top + height {
+                    queue!(out, MoveTo(0, row as u16), Clear(ClearType::CurrentLine))?;
+                }
+                return Ok(());
+            }
+        };
+
+        if self.preview.as_ref().is_none_or(|(path, _)| *path != hit.file_path) {
+            self.preview = Some((hit.file_path.clone(), read_preview_lines(&hit.file_path)));
+        }
+        let lines = &self.preview.as_ref().expect("loaded above").1;
+
+        let title = format!("── {} ", hit.file_path);
+        let fill = width.saturating_sub(title.chars().count());
+        queue!(out, MoveTo(0, top as u16), Clear(ClearType::CurrentLine), SetForegroundColor(Color::DarkGrey))?;
+        queue!(out, Print(fit_width(&format!("{}{}", title, "─".repeat(fill)), width)), ResetColor)?;
+
+        // the hit is kept in the middle of the preview
+        let rows = height - 1;
+        let first_line = hit.line_num.saturating_sub(rows / 2).min(lines.len().saturating_sub(rows));
+
+        for row in 0..rows {
+            queue!(out, MoveTo(0, (top + 1 + row) as u16), Clear(ClearType::CurrentLine))?;
+            let line_num = first_line + row;
+            let line = match lines.get(line_num) {
+                Some(line) => line,
+                None => continue,
+            };
+
+            let is_hit = line_num == hit.line_num;
+            let number = format!("{:>width$} ", line_num + 1, width = LINE_NUM_WIDTH - 1);
+            queue!(out, SetForegroundColor(if is_hit { Color::Green } else { Color::DarkGrey }), Print(number), ResetColor)?;
+
+            let text_width = width.saturating_sub(LINE_NUM_WIDTH);
+            if is_hit {
+                queue!(out, SetAttribute(Attribute::Bold))?;
+                draw_highlighted(out, line, &hit.matches, text_width)?;
+                queue!(out, SetAttribute(Attribute::Reset))?;
+            } else {
+                draw_highlighted(out, line, &[], text_width)?;
+            }
+        }
+
+        return Ok(());
+    }
+}
+
+// "path:line: text", the selected hit is marked and bold
+fn draw_hit(out: &mut Vec<u8>, hit: &Hit, selected: bool, width: usize) -> std::io::Result<()> {
+    let location = format!("{}:{}: ", hit.file_path, hit.line_num + 1);
+    let location = fit_width(&location, width.saturating_sub(2));
+
+    if selected {
+        queue!(out, SetAttribute(Attribute::Bold), Print("> "))?;
+    } else {
+        queue!(out, Print("  "))?;
+    }
+    queue!(out, SetForegroundColor(Color::Magenta), Print(&location), ResetColor)?;
+    if selected {
+        queue!(out, SetAttribute(Attribute::Bold))?;
+    }
+
+    let text_width = width.saturating_sub(2 + location.chars().count());
+    draw_highlighted(out, &hit.line, &hit.matches, text_width)?;
+    return queue!(out, SetAttribute(Attribute::Reset));
+}
+
+// text cut to `width` chars with matches in red; a line is shifted left when its first match
+// wouldn't be visible otherwise
+fn draw_highlighted(out: &mut Vec<u8>, text: &str, matches: &[Match], width: usize) -> std::io::Result<()> {
+    // only the first line of a multiline match is shown
+    let text = text.split('\n').next().unwrap_or_default().trim_end_matches('\r');
+
+    let mut skip_chars = 0;
+    if let Some(first) = matches.first() {
+        let match_col = text[..first.start.min(text.len())].chars().count();
+        if match_col + 1 > width * 2 / 3 {
+            skip_chars = match_col.saturating_sub(width / 3);
+        }
+    }
+
+    let mut in_match = false;
+    for (byte_ind, c) in text.char_indices().skip(skip_chars).take(width) {
+
+        let match_here = matches.iter().any(|found| byte_ind >= found.start && byte_ind < found.end);
+        if match_here != in_match {
+            if match_here {
+                queue!(out, SetForegroundColor(Color::Red))?;
+            } else {
+                queue!(out, SetForegroundColor(Color::Reset))?;
+            }
+            in_match = match_here;
+        }
+
+        // control chars would move the cursor around
+        let c = if c == '\t' || c.is_control() { ' ' } else { c };
+        queue!(out, Print(c))?;
+    }
+
+    return queue!(out, ResetColor);
+}
+
+fn fit_width(text: &str, width: usize) -> String {
+    return text.chars().take(width).collect();
+}
+
+// lines of a file as they are on disk, members of archives and huge files have no preview
+fn read_preview_lines(file_path: &str) -> Vec<String> {
+    let too_big = std::fs::metadata(file_path).map_or(true, |metadata| metadata.len() > MAX_PREVIEW_FILE_SIZE);
+    if too_big {
+        return vec!["(no preview)".to_string()];
+    }
+
+    match std::fs::read(file_path) {
+        Ok(contents) => return String::from_utf8_lossy(&contents).lines().map(|line| line.to_string()).collect(),
+        Err(err) => return vec![format!("(no preview: {})", err)],
+    }
+}
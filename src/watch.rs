@@ -0,0 +1,321 @@
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::io::ErrorKind;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+
+use crate::cli_input::{OptFlag, UserInput};
+use crate::cli_output::{print_watch_changes, write_watch_changes_to_output_file};
+use crate::fs_related::{search_changed_file, DirWalker, FileData};
+use crate::str_pattern_match::{Matcher, Occurence};
+
+const EVENTS_BUFF_SIZE: usize = 64 * 1024;
+// events which come shortly after the first one are handled together,
+// so a file written in a few chunks is searched once
+const SETTLE_TIME: Duration = Duration::from_millis(100);
+
+struct WatchedDir {
+    path:       PathBuf,
+    // depth of entries of the directory, as in DirWalker
+    depth:      usize,
+    id:         (u64, u64),
+}
+
+// keeps searching files under the search path as they change, reporting matches which appear or disappear
+pub struct Watcher<'a, M: Matcher + ?Sized> {
+    user_input:         &'a UserInput,
+    matcher:            &'a M,
+    inotify:            Inotify,
+    quiet:              bool,
+    // decides which entries are searched and which directories are watched, same as for the initial search
+    walker:             DirWalker<'a>,
+    dirs:               HashMap<WatchDescriptor, WatchedDir>,
+    // (device, inode) of watched directories, a directory reached by a few paths is watched once
+    dir_ids:            HashSet<(u64, u64)>,
+    // without -d and -r only the search path itself is searched, its directory is watched for it
+    single_file:        Option<OsString>,
+    // occurences by file path, files without matches are not kept
+    matches:            HashMap<String, Vec<Occurence>>,
+}
+
+impl<'a, M: Matcher + ?Sized> Watcher<'a, M> {
+    // `file_data` is the result of the initial search, changes are reported relative to it
+    pub fn new(user_input: &'a UserInput, matcher: &'a M, file_data: Vec<FileData>) -> std::io::Result<Self> {
+        let mut walker = DirWalker::from_user_input(user_input);
+        walker.set_root(&user_input.search_path);
+
+        let mut watcher = Self {
+            user_input,
+            matcher,
+            inotify:            Inotify::init()?,
+            quiet:              user_input.has_opt_flag(OptFlag::Quiet),
+            walker,
+            dirs:               HashMap::new(),
+            dir_ids:            HashSet::new(),
+            single_file:        None,
+            matches:            file_data.into_iter().map(|file_d| (file_d.file_path, file_d.occurences)).collect(),
+        };
+
+        let do_dir_search = user_input.has_opt_flag(OptFlag::Recursive) || user_input.has_opt_flag(OptFlag::Dir);
+        let search_path = Path::new(&user_input.search_path);
+        if do_dir_search {
+            watcher.add_dir_watch(search_path, 1)?;
+            watcher.watch_subdirs(search_path, 1, &mut Vec::new());
+        } else {
+            // a file may be replaced by another one (log rotation, editors saving through a temporary file),
+            // only watching its directory catches that
+            let file_name = search_path.file_name()
+                .ok_or_else(|| std::io::Error::new(ErrorKind::InvalidInput, "search path doesn't point to a file"))?;
+            let parent = match search_path.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent,
+                _ => Path::new("."),
+            };
+            watcher.single_file = Some(file_name.to_os_string());
+            watcher.add_dir_watch(parent, 1)?;
+        }
+
+        return Ok(watcher);
+    }
+
+    // never returns unless reading events fails
+    pub fn run(&mut self) -> std::io::Result<()> {
+        let mut buffer = vec![0u8; EVENTS_BUFF_SIZE];
+
+        loop {
+            let mut changed_files = Vec::<String>::new();
+
+            let events: Vec<(WatchDescriptor, EventMask, Option<OsString>)> = self.inotify.read_events_blocking(&mut buffer)?
+                .map(|event| (event.wd, event.mask, event.name.map(|name| name.to_os_string())))
+                .collect();
+            self.handle_events(events, &mut changed_files);
+
+            std::thread::sleep(SETTLE_TIME);
+            loop {
+                let events: Vec<(WatchDescriptor, EventMask, Option<OsString>)> = match self.inotify.read_events(&mut buffer) {
+                    Ok(events) => events.map(|event| (event.wd, event.mask, event.name.map(|name| name.to_os_string()))).collect(),
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                    Err(err) => return Err(err),
+                };
+                self.handle_events(events, &mut changed_files);
+            }
+
+            for file_path in changed_files.iter() {
+                self.update_file(file_path);
+            }
+        }
+    }
+
+    // directories are handled right away, files are collected into `changed_files` to be searched once
+    fn handle_events(&mut self, events: Vec<(WatchDescriptor, EventMask, Option<OsString>)>, changed_files: &mut Vec<String>) {
+        for (wd, mask, name) in events {
+            if mask.contains(EventMask::Q_OVERFLOW) {
+                if !self.quiet {
+                    eprintln!("Too many changes at once, some of them were missed");
+                }
+                continue;
+            }
+
+            // the directory was removed or moved away, the watch is gone with it
+            if mask.contains(EventMask::IGNORED) {
+                if let Some(dir) = self.dirs.remove(&wd) {
+                    self.dir_ids.remove(&dir.id);
+                }
+                continue;
+            }
+
+            let (dir_path, depth) = match (self.dirs.get(&wd), &name) {
+                (Some(dir), Some(_)) => (dir.path.clone(), dir.depth),
+                _ => continue,
+            };
+            let name = name.expect("checked above");
+
+            if let Some(single_file) = &self.single_file {
+                if *single_file == name && !mask.contains(EventMask::ISDIR) {
+                    push_unique(changed_files, self.user_input.search_path.clone());
+                }
+                continue;
+            }
+
+            let entry_path = match self.accepted_entry(&dir_path.join(&name)) {
+                Some(entry_path) => entry_path,
+                None => continue,
+            };
+
+            if !mask.contains(EventMask::ISDIR) {
+                if self.walker.depth_allowed(depth) {
+                    push_unique(changed_files, entry_path);
+                }
+                continue;
+            }
+
+            if mask.intersects(EventMask::DELETE | EventMask::MOVED_FROM) {
+                self.forget_dir(&entry_path);
+            } else if mask.intersects(EventMask::CREATE | EventMask::MOVED_TO) && self.walker.descends_below(depth) {
+                // files may be created in the directory before it's watched, so all of them are searched
+                let entry_path = PathBuf::from(entry_path);
+                match self.add_dir_watch(&entry_path, depth + 1) {
+                    Ok(true) => self.watch_subdirs(&entry_path, depth + 1, changed_files),
+                    Ok(false) => (),
+                    Err(err) => {
+                        if !self.quiet {
+                            eprintln!("Can't watch the directory: {}\nError: {}", entry_path.display(), err);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // returns false if the directory is already watched or the walk wouldn't enter it
+    fn add_dir_watch(&mut self, dir_path: &Path, depth: usize) -> std::io::Result<bool> {
+        let metadata = std::fs::metadata(dir_path)?;
+        let id = (metadata.dev(), metadata.ino());
+        if self.dir_ids.contains(&id) || !self.walker.on_root_filesystem(metadata.dev()) {
+            return Ok(false);
+        }
+
+        let mask = WatchMask::CLOSE_WRITE | WatchMask::MODIFY | WatchMask::CREATE | WatchMask::DELETE
+            | WatchMask::MOVED_FROM | WatchMask::MOVED_TO;
+        let wd = self.inotify.watches().add(dir_path, mask)?;
+
+        self.dir_ids.insert(id);
+        self.dirs.insert(wd, WatchedDir {
+            path: dir_path.to_path_buf(),
+            depth,
+            id,
+        });
+        return Ok(true);
+    }
+
+    // watches subdirectories of `dir_path` as deep as the walk would go, files which may be searched are added to `files`
+    fn watch_subdirs(&mut self, dir_path: &Path, depth: usize, files: &mut Vec<String>) {
+        let dir_iter = match std::fs::read_dir(dir_path) {
+            Ok(it) => it,
+            Err(err) => {
+                if !self.quiet {
+                    eprintln!("Can't read the directory: {}\nError: {}", dir_path.display(), err);
+                }
+                return;
+            }
+        };
+
+        for dir_entry in dir_iter.flatten() {
+            let entry_path = match self.accepted_entry(&dir_entry.path()) {
+                Some(entry_path) => entry_path,
+                None => continue,
+            };
+            let metadata = match std::fs::metadata(&entry_path) {
+                Ok(metadata) => metadata,
+                Err(_) => continue,
+            };
+
+            if metadata.is_file() {
+                if self.walker.depth_allowed(depth) {
+                    files.push(entry_path);
+                }
+            } else if metadata.is_dir() && self.walker.descends_below(depth) {
+                let entry_path = PathBuf::from(entry_path);
+                match self.add_dir_watch(&entry_path, depth + 1) {
+                    Ok(true) => self.watch_subdirs(&entry_path, depth + 1, files),
+                    Ok(false) => (),
+                    Err(err) => {
+                        if !self.quiet {
+                            eprintln!("Can't watch the directory: {}\nError: {}", entry_path.display(), err);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // same filters as the walk applies to an entry, returns its path as a string
+    fn accepted_entry(&self, entry_path: &Path) -> Option<String> {
+        // a removed entry can't be checked, it's only forgotten
+        let is_symlink = std::fs::symlink_metadata(entry_path).is_ok_and(|metadata| metadata.file_type().is_symlink());
+        return self.walker.accepts_entry(entry_path, is_symlink).ok();
+    }
+
+    // searches the file again and reports the difference, a removed file loses all its matches
+    fn update_file(&mut self, file_path: &str) {
+        // as in the walk, filters only apply to files found in directories; a file which stops
+        // passing them (grows too big, for example) loses its matches
+        let filtered_out = self.single_file.is_none() && !self.walker.accepts_file(file_path);
+        let found = if Path::new(file_path).is_file() && !filtered_out {
+            search_changed_file(self.user_input, self.matcher, file_path)
+        } else {
+            Vec::new()
+        };
+
+        // members of an archive are kept as separate files
+        let member_prefix = format!("{}!/", file_path);
+        let mut previous = self.take_matches(|path| path == file_path || path.starts_with(&member_prefix));
+
+        for file_d in found {
+            let previous_occurences = previous.remove(&file_d.file_path).unwrap_or_default();
+            self.report_changes(&file_d.file_path, &previous_occurences, &file_d.occurences);
+            self.matches.insert(file_d.file_path, file_d.occurences);
+        }
+        self.report_removed(previous);
+    }
+
+    fn forget_dir(&mut self, dir_path: &str) {
+        let dir_prefix = format!("{}/", dir_path.trim_end_matches('/'));
+        let previous = self.take_matches(|path| path.starts_with(&dir_prefix));
+        self.report_removed(previous);
+    }
+
+    fn take_matches<F: Fn(&str) -> bool>(&mut self, select: F) -> HashMap<String, Vec<Occurence>> {
+        let paths: Vec<String> = self.matches.keys().filter(|path| select(path)).cloned().collect();
+        return paths.into_iter()
+            .filter_map(|path| self.matches.remove(&path).map(|occurences| (path, occurences)))
+            .collect();
+    }
+
+    fn report_removed(&self, previous: HashMap<String, Vec<Occurence>>) {
+        let mut previous: Vec<(String, Vec<Occurence>)> = previous.into_iter().collect();
+        previous.sort_by(|a, b| a.0.cmp(&b.0));
+        for (file_path, occurences) in previous.iter() {
+            self.report_changes(file_path, occurences, &[]);
+        }
+    }
+
+    fn report_changes(&self, file_path: &str, previous: &[Occurence], current: &[Occurence]) {
+        let removed = missing_occurences(previous, current);
+        let added = missing_occurences(current, previous);
+
+        match &self.user_input.output_file_path {
+            Some(output_file_path) => write_watch_changes_to_output_file(file_path, &added, &removed, output_file_path, self.user_input),
+            None => print_watch_changes(file_path, &added, &removed, self.user_input),
+        }
+    }
+}
+
+// occurences of `from` which `to` doesn't have, compared by their text only,
+// so lines which just moved because of an edit above them are not reported
+fn missing_occurences<'o>(from: &'o [Occurence], to: &[Occurence]) -> Vec<&'o Occurence> {
+    let mut counts = HashMap::<&str, usize>::new();
+    for occurence in to.iter() {
+        *counts.entry(occurence.token.contents()).or_insert(0) += 1;
+    }
+
+    return from.iter()
+        .filter(|occurence| {
+            match counts.get_mut(occurence.token.contents()) {
+                Some(count) if *count > 0 => {
+                    *count -= 1;
+                    return false;
+                },
+                _ => return true,
+            }
+        })
+        .collect();
+}
+
+fn push_unique(paths: &mut Vec<String>, path: String) {
+    if !paths.contains(&path) {
+        paths.push(path);
+    }
+}